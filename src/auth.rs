@@ -0,0 +1,126 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::str;
+
+use rustc_serialize::base64::FromBase64;
+use rustc_serialize::json;
+
+pub enum Credentials {
+    Password { username: String, password: String },
+    // A refresh token for the registry's token service, which some
+    // registries hand out on `docker login`
+    IdentityToken(String),
+}
+
+// Docker keeps Docker Hub credentials under the url of its old index
+const DOCKER_HUB_SERVER: &'static str = "https://index.docker.io/v1/";
+
+// Registry keys in the docker config may be a host[:port] or a url
+fn key_host(key: &str) -> &str {
+    let host = key.find("://").map_or(key, |i| &key[i + 3..]);
+    let host = host.split('/').next().unwrap();
+    match host {
+        "docker.io" | "registry-1.docker.io" => "index.docker.io",
+        host => host,
+    }
+}
+
+#[allow(non_snake_case)]
+#[derive(RustcDecodable)]
+struct DockerConfig {
+    auths: Option<BTreeMap<String, AuthEntry>>,
+    credHelpers: Option<BTreeMap<String, String>>,
+    credsStore: Option<String>,
+}
+
+#[derive(RustcDecodable)]
+struct AuthEntry {
+    auth: Option<String>,
+    identitytoken: Option<String>,
+}
+
+fn config_path() -> Option<PathBuf> {
+    match env::var_os("DOCKER_CONFIG") {
+        Some(dir) => Some(PathBuf::from(dir).join("config.json")),
+        None => env::var_os("HOME").map(|home| PathBuf::from(home).join(".docker").join("config.json")),
+    }
+}
+
+// Ask a docker-credential-<helper> executable for a registry's credentials
+fn helper_credentials(helper: &str, server: &str) -> Option<Credentials> {
+    let program = format!("docker-credential-{}", helper);
+    let child = Command::new(&program).arg("get")
+                                      .stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::null())
+                                      .spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(e) => {
+            println!("Could not run credential helper {}: {}", program, e);
+            return None
+        },
+    };
+    child.stdin.take().unwrap().write_all(server.as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    // Helpers fail when they have nothing for the registry
+    if !output.status.success() {
+        return None
+    }
+    #[allow(non_snake_case)]
+    #[derive(RustcDecodable)]
+    struct HelperCredentials { Username: String, Secret: String }
+    let creds: HelperCredentials = json::decode(str::from_utf8(&output.stdout).unwrap()).unwrap();
+    Some(if creds.Username == "<token>" {
+        Credentials::IdentityToken(creds.Secret)
+    } else {
+        Credentials::Password { username: creds.Username, password: creds.Secret }
+    })
+}
+
+// Credentials for a registry (host[:port]) saved by `docker login`
+pub fn find_credentials(host: &str) -> Option<Credentials> {
+    config_path().and_then(|path| config_credentials(&path, host))
+}
+
+// Credentials for a registry from a docker config, or the credential helper it
+// names for the registry
+pub fn config_credentials(path: &Path, host: &str) -> Option<Credentials> {
+    let mut configjson = String::new();
+    match File::open(path) {
+        Ok(mut file) => file.read_to_string(&mut configjson).unwrap(),
+        Err(_) => return None,
+    };
+    let config: DockerConfig = json::decode(&configjson)
+                                   .unwrap_or_else(|e| panic!("Could not parse {}: {}", path.display(), e));
+    let host = key_host(host);
+    let server = if host == "index.docker.io" { DOCKER_HUB_SERVER } else { host };
+
+    let helper = config.credHelpers.as_ref()
+                                   .and_then(|helpers| helpers.iter().find(|&(key, _)| key_host(key) == host))
+                                   .map(|(_, helper)| helper)
+                                   .or(config.credsStore.as_ref());
+    if let Some(creds) = helper.and_then(|helper| helper_credentials(helper, server)) {
+        return Some(creds)
+    }
+
+    let entry = match config.auths.as_ref().and_then(|auths| auths.iter().find(|&(key, _)| key_host(key) == host)) {
+        Some((_, entry)) => entry,
+        None => return None,
+    };
+    if let Some(ref token) = entry.identitytoken {
+        if !token.is_empty() {
+            return Some(Credentials::IdentityToken(token.clone()))
+        }
+    }
+    match entry.auth {
+        Some(ref auth) if !auth.is_empty() => {
+            let auth = String::from_utf8(auth.from_base64().unwrap()).unwrap();
+            let colonpos = auth.find(':').unwrap_or_else(|| panic!("Invalid auth for {} in {}", host, path.display()));
+            Some(Credentials::Password { username: auth[..colonpos].to_owned(), password: auth[colonpos + 1..].to_owned() })
+        },
+        _ => None,
+    }
+}
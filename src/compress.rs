@@ -0,0 +1,191 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter};
+use std::io::prelude::*;
+use std::path::Path;
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use flate2;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use zstd;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    pub fn parse(name: &str) -> Compression {
+        match name {
+            "none" => Compression::None,
+            "gzip" => Compression::Gzip,
+            "zstd" => Compression::Zstd,
+            _ => panic!("unknown compression: {}", name),
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match *self {
+            Compression::None => "",
+            Compression::Gzip => ".gz",
+            Compression::Zstd => ".zst",
+        }
+    }
+
+    // Work out the compression from the first few bytes of a file
+    pub fn detect(file: &mut File) -> Compression {
+        let mut magic = vec![];
+        file.seek(io::SeekFrom::Start(0)).unwrap();
+        (&mut *file).take(4).read_to_end(&mut magic).unwrap();
+        file.seek(io::SeekFrom::Start(0)).unwrap();
+        if magic.starts_with(&[0x1f, 0x8b]) {
+            Compression::Gzip
+        } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+}
+
+// An input layer, decompressed on the fly if necessary. It can only move
+// forwards, so compressed and uncompressed layers are treated the same way.
+pub struct LayerReader {
+    // Kept separately when uncompressed so skipping can seek
+    file: Option<BufReader<File>>,
+    stream: Option<Box<Read>>,
+    pos: u64,
+}
+
+impl LayerReader {
+    pub fn open(path: &Path) -> LayerReader {
+        let mut file = File::open(path).unwrap_or_else(|e| panic!("Could not open {}: {}", path.display(), e));
+        let compression = Compression::detect(&mut file);
+        let file = BufReader::with_capacity(1024 * 1024, file);
+        let (file, stream): (Option<BufReader<File>>, Option<Box<Read>>) = match compression {
+            Compression::None => (Some(file), None),
+            Compression::Gzip => (None, Some(Box::new(GzDecoder::new(file).unwrap()))),
+            Compression::Zstd => (None, Some(Box::new(zstd::stream::Decoder::new(file).unwrap()))),
+        };
+        LayerReader { file: file, stream: stream, pos: 0 }
+    }
+
+    // Move to an offset in the uncompressed tar, which must not be behind us
+    pub fn skip_to(&mut self, offset: u64) {
+        assert!(offset >= self.pos, "layer read out of order");
+        if offset == self.pos {
+            return
+        }
+        if let Some(ref mut file) = self.file {
+            file.seek(io::SeekFrom::Start(offset)).unwrap();
+            self.pos = offset;
+            return
+        }
+        let toskip = offset - self.pos;
+        let skipped = io::copy(&mut self.take(toskip), &mut io::sink()).unwrap();
+        assert!(skipped == toskip, "layer truncated");
+    }
+}
+
+impl Read for LayerReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let numread = match (&mut self.file, &mut self.stream) {
+            (&mut Some(ref mut file), _) => try!(file.read(buf)),
+            (_, &mut Some(ref mut stream)) => try!(stream.read(buf)),
+            _ => unreachable!(),
+        };
+        self.pos += numread as u64;
+        Ok(numread)
+    }
+}
+
+// What's needed to refer to a layer from an image config and manifest
+#[derive(Clone, RustcEncodable)]
+pub struct LayerDigests {
+    // Digest of the uncompressed tar
+    pub diff_id: String,
+    // Digest and size of the file as written
+    pub digest: String,
+    pub size: u64,
+}
+
+struct HashWriter<W> {
+    inner: W,
+    hasher: Sha256,
+    count: u64,
+}
+
+impl<W: Write> Write for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let numwritten = try!(self.inner.write(buf));
+        self.hasher.input(&buf[..numwritten]);
+        self.count += numwritten as u64;
+        Ok(numwritten)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+enum Encoder {
+    None(HashWriter<BufWriter<File>>),
+    Gzip(GzEncoder<HashWriter<BufWriter<File>>>),
+    Zstd(zstd::stream::Encoder<HashWriter<BufWriter<File>>>),
+}
+
+// An output layer, compressed as requested, which works out its digests as
+// it's written
+pub struct LayerWriter {
+    encoder: Encoder,
+    diffhasher: Sha256,
+}
+
+impl LayerWriter {
+    pub fn create(path: &Path, compression: Compression) -> LayerWriter {
+        let file = File::create(path).unwrap_or_else(|e| panic!("Could not create {}: {}", path.display(), e));
+        let out = HashWriter { inner: BufWriter::new(file), hasher: Sha256::new(), count: 0 };
+        let encoder = match compression {
+            Compression::None => Encoder::None(out),
+            Compression::Gzip => Encoder::Gzip(GzEncoder::new(out, flate2::Compression::Default)),
+            Compression::Zstd => Encoder::Zstd(zstd::stream::Encoder::new(out, 3).unwrap()),
+        };
+        LayerWriter { encoder: encoder, diffhasher: Sha256::new() }
+    }
+
+    pub fn finish(mut self) -> LayerDigests {
+        let mut out = match self.encoder {
+            Encoder::None(out) => out,
+            Encoder::Gzip(encoder) => encoder.finish().unwrap(),
+            Encoder::Zstd(encoder) => encoder.finish().unwrap(),
+        };
+        out.flush().unwrap();
+        LayerDigests {
+            diff_id: format!("sha256:{}", self.diffhasher.result_str()),
+            digest: format!("sha256:{}", out.hasher.result_str()),
+            size: out.count,
+        }
+    }
+}
+
+impl Write for LayerWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let numwritten = try!(match self.encoder {
+            Encoder::None(ref mut out) => out.write(buf),
+            Encoder::Gzip(ref mut encoder) => encoder.write(buf),
+            Encoder::Zstd(ref mut encoder) => encoder.write(buf),
+        });
+        self.diffhasher.input(&buf[..numwritten]);
+        Ok(numwritten)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        match self.encoder {
+            Encoder::None(ref mut out) => out.flush(),
+            Encoder::Gzip(ref mut encoder) => encoder.flush(),
+            Encoder::Zstd(ref mut encoder) => encoder.flush(),
+        }
+    }
+}
@@ -0,0 +1,210 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ffi::CString;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::os::raw::{c_char, c_void};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::str;
+
+use tar;
+use tar::Archive;
+
+use compress::{Compression, LayerDigests, LayerWriter};
+use layer::{HashableHeader, append_entry, normpath};
+use overlay::{EntrySource, overlay_layers, whiteouts, write_layer};
+use util::sha256_hex;
+
+type Entries = BTreeMap<PathBuf, (HashableHeader, EntrySource)>;
+
+fn set_octal(field: &mut [u8], num: u64) {
+    let len = field.len() - 1;
+    let digits = format!("{:0width$o}", num, width = len);
+    field[..len].copy_from_slice(digits.as_bytes());
+    field[len] = 0;
+}
+
+extern "C" {
+    fn llistxattr(path: *const c_char, list: *mut c_char, size: usize) -> isize;
+    fn lgetxattr(path: *const c_char, name: *const c_char, value: *mut c_void, size: usize) -> isize;
+}
+
+// Extended attributes of a path (not following symlinks), e.g. file
+// capabilities, which must be kept like tar would keep them. Filesystems
+// without xattrs have none.
+fn read_xattrs(path: &Path) -> Vec<(String, Vec<u8>)> {
+    let cpath = CString::new(path.as_os_str().as_bytes()).unwrap();
+    let listlen = unsafe { llistxattr(cpath.as_ptr(), ptr::null_mut(), 0) };
+    if listlen <= 0 {
+        return vec![]
+    }
+    let mut list = vec![0u8; listlen as usize];
+    let listlen = unsafe { llistxattr(cpath.as_ptr(), list.as_mut_ptr() as *mut c_char, list.len()) };
+    assert!(listlen >= 0, "Could not list xattrs of {}: {}", path.display(), io::Error::last_os_error());
+    list.truncate(listlen as usize);
+
+    let mut xattrs = vec![];
+    for name in list.split(|&b| b == 0).filter(|name| !name.is_empty()) {
+        let cname = CString::new(name).unwrap();
+        let get = |value: &mut [u8]| unsafe {
+            lgetxattr(cpath.as_ptr(), cname.as_ptr(), value.as_mut_ptr() as *mut c_void, value.len())
+        };
+        let valuelen = get(&mut []);
+        assert!(valuelen >= 0, "Could not read xattrs of {}: {}", path.display(), io::Error::last_os_error());
+        let mut value = vec![0u8; valuelen as usize];
+        let valuelen = get(&mut value);
+        assert!(valuelen >= 0, "Could not read xattrs of {}: {}", path.display(), io::Error::last_os_error());
+        value.truncate(valuelen as usize);
+        xattrs.push((str::from_utf8(name).unwrap().to_owned(), value))
+    }
+    xattrs
+}
+
+// Headers for everything under a directory, like tar would make them. A file
+// with several links is stored at its first path, the rest being hard links.
+fn dir_entries(root: &Path) -> Entries {
+    let mut paths = vec![];
+    let mut dirs = vec![PathBuf::new()];
+    while let Some(dir) = dirs.pop() {
+        for dirent in fs::read_dir(root.join(&dir)).unwrap() {
+            let path = dir.join(dirent.unwrap().file_name());
+            if fs::symlink_metadata(root.join(&path)).unwrap().is_dir() {
+                dirs.push(path.clone())
+            }
+            paths.push(path)
+        }
+    }
+    paths.sort();
+
+    let mut inodes: HashMap<(u64, u64), PathBuf> = HashMap::new();
+    let mut entries = Entries::new();
+    for path in paths {
+        let fullpath = root.join(&path);
+        let meta = fs::symlink_metadata(&fullpath).unwrap();
+        let ftype = meta.file_type();
+        let inode = (meta.dev(), meta.ino());
+        let mut hheader = HashableHeader::new(&tar::Header::new());
+        hheader.0.set_mode(meta.mode() & 0o7777);
+        hheader.0.set_uid(meta.uid());
+        hheader.0.set_gid(meta.gid());
+        hheader.0.set_mtime(meta.mtime() as u64);
+        hheader.0.set_size(0);
+        hheader.set_path(&path);
+        for (name, value) in read_xattrs(&fullpath) {
+            hheader.set_xattr(&name, &value);
+        }
+        let mut digest = sha256_hex(&mut io::empty());
+        if ftype.is_file() && inodes.contains_key(&inode) {
+            hheader.0.link[0] = b'1';
+            hheader.set_link_name(&inodes[&inode]);
+        } else if ftype.is_file() {
+            hheader.0.link[0] = b'0';
+            hheader.0.set_size(meta.len());
+            digest = sha256_hex(&mut File::open(&fullpath).unwrap());
+            if meta.nlink() > 1 {
+                inodes.insert(inode, path.clone());
+            }
+        } else if ftype.is_dir() {
+            hheader.0.link[0] = b'5';
+        } else if ftype.is_symlink() {
+            hheader.0.link[0] = b'2';
+            hheader.set_link_name(&fs::read_link(&fullpath).unwrap());
+        } else if ftype.is_char_device() || ftype.is_block_device() {
+            hheader.0.link[0] = if ftype.is_char_device() { b'3' } else { b'4' };
+            let rdev = meta.rdev();
+            set_octal(&mut hheader.0.dev_major, ((rdev >> 8) & 0xfff) | ((rdev >> 32) & !0xfff));
+            set_octal(&mut hheader.0.dev_minor, (rdev & 0xff) | ((rdev >> 12) & !0xff));
+        } else if ftype.is_fifo() {
+            hheader.0.link[0] = b'6';
+        } else {
+            // Sockets can't go in a tar
+            continue
+        }
+        entries.insert(path, (hheader, EntrySource { layer: 0, offset: 0, digest: digest }));
+    }
+    entries
+}
+
+// A filesystem to diff - a directory, or a tar of one (e.g. from `docker
+// export` or `dayer squash`)
+fn read_fs(path: &Path) -> Entries {
+    println!("Reading {}", path.display());
+    let mut entries = if path.is_dir() {
+        dir_entries(path)
+    } else {
+        overlay_layers(&[path][..], false).entries
+    };
+    // The root, from tars made of `.`
+    entries.remove(Path::new(""));
+    entries
+}
+
+// Whether an entry has changed. Directories don't record user and group
+// names or access and change times, so those are ignored, as is how the path
+// was written (e.g. with a leading `./`) as entries are found by normalised
+// path.
+fn changed(old: &(HashableHeader, EntrySource), new: &(HashableHeader, EntrySource)) -> bool {
+    let isdevice = (new.0).0.link[0] == b'3' || (new.0).0.link[0] == b'4';
+    old.1.digest != new.1.digest || old.0.differences(&new.0).iter().any(|field| match *field {
+        "path" | "uname" | "gname" | "atime" | "ctime" | "format" => false,
+        "device" => isdevice,
+        _ => true,
+    })
+}
+
+// Make a layer which turns the old filesystem into the new one - everything
+// added or changed in the new one, plus whiteouts for anything removed. Each
+// can be a directory or a tar.
+pub fn diff_layer(oldpath: &Path, newpath: &Path, outpath: &Path, compression: Compression) -> LayerDigests {
+    let old = read_fs(oldpath);
+    let new = read_fs(newpath);
+
+    let mut keep: BTreeSet<PathBuf> = new.iter()
+                                         .filter(|&(path, entry)| old.get(path).map_or(true, |o| changed(o, entry)))
+                                         .map(|(path, _)| path.clone())
+                                         .collect();
+    // Hard links must have their target in the same layer, and links to a
+    // changed file must come with it or they'd keep the old contents
+    loop {
+        let mut linked = vec![];
+        for (path, &(ref hheader, _)) in &new {
+            if !hheader.is_hard_link() {
+                continue
+            }
+            let target = normpath(&hheader.link_name().unwrap());
+            if keep.contains(path) && !keep.contains(&target) && new.contains_key(&target) {
+                linked.push(target)
+            } else if keep.contains(&target) && !keep.contains(path) {
+                linked.push(path.clone())
+            }
+        }
+        if linked.is_empty() {
+            break
+        }
+        keep.extend(linked);
+    }
+    let removed: Vec<&Path> = old.keys().filter(|path| !new.contains_key(*path)).map(|path| path.as_path()).collect();
+    let whiteouts = whiteouts(&removed, &new);
+
+    let entries: Vec<&(HashableHeader, EntrySource)> = keep.iter().map(|path| &new[path]).collect();
+    println!("Writing {} entries and {} whiteouts to {}", entries.len(), whiteouts.len(), outpath.display());
+    if !newpath.is_dir() {
+        return write_layer(outpath, compression, &[newpath][..], &whiteouts, &entries)
+    }
+    let outar = Archive::new(LayerWriter::create(outpath, compression));
+    for whiteout in &whiteouts {
+        append_entry(&outar, whiteout, &mut io::empty());
+    }
+    for (path, &&(ref hheader, _)) in keep.iter().zip(&entries) {
+        if hheader.size() == 0 {
+            append_entry(&outar, hheader, &mut io::empty());
+        } else {
+            append_entry(&outar, hheader, &mut File::open(newpath.join(path)).unwrap());
+        }
+    }
+    outar.finish().unwrap();
+    outar.into_inner().finish()
+}
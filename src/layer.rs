@@ -0,0 +1,549 @@
+use std::cmp;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::io::prelude::*;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::str;
+
+use tar;
+use tar::Archive;
+
+// PAX extended header records, values may be binary (e.g. xattrs)
+pub type PaxRecords = BTreeMap<String, Vec<u8>>;
+
+const XATTR_PREFIX: &'static str = "SCHILY.xattr.";
+
+// Layer whiteouts - an empty file named with the prefix hides the file without
+// it from lower layers, the opaque whiteout hides everything in its directory
+pub const WHITEOUT_PREFIX: &'static str = ".wh.";
+pub const OPAQUE_WHITEOUT: &'static str = ".wh..wh..opq";
+
+// https://github.com/rust-lang/rust/issues/13721
+// A tar header along with the extended header records that apply to it (GNU
+// long names are turned into PAX records) and, for GNU sparse files, the raw
+// sparse map extension blocks that sit between the header and the data - all
+// are considered when comparing
+#[derive(Clone)]
+pub struct HashableHeader(pub tar::Header, pub PaxRecords, pub Vec<u8>);
+impl HashableHeader {
+    pub fn new(srcheader: &tar::Header) -> HashableHeader {
+        HashableHeader(srcheader.clone(), PaxRecords::new(), vec![])
+    }
+    // Only for records that are always ASCII, like numbers - paths and names
+    // may be in any encoding (hdrcharset=BINARY)
+    fn pax_str(&self, key: &str) -> Option<&str> {
+        self.1.get(key).map(|val| str::from_utf8(val).unwrap())
+    }
+    fn pax_path(&self, key: &str) -> Option<PathBuf> {
+        self.1.get(key).map(|val| PathBuf::from(OsStr::from_bytes(val)))
+    }
+    fn pax_name(&self, key: &str) -> Option<String> {
+        self.1.get(key).map(|val| String::from_utf8_lossy(val).into_owned())
+    }
+    fn pax_num(&self, key: &str) -> Option<u64> {
+        self.pax_str(key).map(|val| u64::from_str_radix(val, 10).unwrap())
+    }
+    pub fn path(&self) -> PathBuf {
+        // PAX format 1.0 sparse files have a placeholder in the path
+        match self.pax_path("GNU.sparse.name").or_else(|| self.pax_path("path")) {
+            Some(path) => path,
+            None => self.0.path().unwrap().to_path_buf(),
+        }
+    }
+    pub fn link_name(&self) -> Option<PathBuf> {
+        match self.pax_path("linkpath") {
+            Some(linkpath) => Some(linkpath),
+            None => self.0.link_name().unwrap().map(|p| p.to_path_buf()),
+        }
+    }
+    // Size of the data in the archive - see real_size for sparse files
+    pub fn size(&self) -> u64 {
+        self.pax_num("size").unwrap_or_else(|| numeric_from(&self.0.size))
+    }
+    pub fn is_dir(&self) -> bool {
+        // GNU dumpdirs are directories with a listing of contents as data
+        self.0.link[0] == b'5' || self.0.link[0] == b'D'
+    }
+    pub fn is_sparse(&self) -> bool {
+        self.0.link[0] == b'S' || self.1.contains_key("GNU.sparse.major") ||
+            self.1.contains_key("GNU.sparse.numblocks")
+    }
+    // Size of the file once extracted, which differs from the size in the
+    // archive for sparse files
+    pub fn real_size(&self) -> u64 {
+        if self.0.link[0] == b'S' {
+            numeric_from(&head_bytes(&self.0)[GNU_REALSIZE_OFFSET..GNU_REALSIZE_OFFSET + 12])
+        } else if let Some(realsize) = self.pax_num("GNU.sparse.realsize").or_else(|| self.pax_num("GNU.sparse.size")) {
+            realsize
+        } else {
+            self.size()
+        }
+    }
+    // (offset, numbytes) of each chunk of data in a sparse file. For PAX
+    // format 1.0 sparse files the map is at the start of the data and so isn't
+    // available here.
+    pub fn sparse_map(&self) -> Option<Vec<(u64, u64)>> {
+        if self.0.link[0] == b'S' {
+            let bytes = head_bytes(&self.0);
+            let mut map = parse_sparse_entries(&bytes[GNU_SPARSE_OFFSET..GNU_ISEXTENDED_OFFSET]);
+            for block in self.2.chunks(512) {
+                map.extend(parse_sparse_entries(&block[..GNU_EXT_ISEXTENDED_OFFSET]))
+            }
+            Some(map)
+        } else if let Some(map) = self.pax_str("GNU.sparse.map") {
+            let nums: Vec<u64> = map.split(',').map(|n| u64::from_str_radix(n, 10).unwrap()).collect();
+            Some(nums.chunks(2).map(|pair| (pair[0], pair[1])).collect())
+        } else {
+            None
+        }
+    }
+    pub fn uid(&self) -> u64 {
+        self.pax_num("uid").unwrap_or_else(|| self.0.uid().unwrap() as u64)
+    }
+    pub fn gid(&self) -> u64 {
+        self.pax_num("gid").unwrap_or_else(|| self.0.gid().unwrap() as u64)
+    }
+    pub fn uname(&self) -> String {
+        self.pax_name("uname").unwrap_or_else(|| self.0.username().unwrap_or("").to_owned())
+    }
+    pub fn gname(&self) -> String {
+        self.pax_name("gname").unwrap_or_else(|| self.0.groupname().unwrap_or("").to_owned())
+    }
+    // Kept as a string to preserve sub-second precision
+    pub fn mtime(&self) -> String {
+        self.pax_str("mtime").map(|mtime| mtime.to_owned())
+                             .unwrap_or_else(|| self.0.mtime().unwrap().to_string())
+    }
+    pub fn xattrs(&self) -> BTreeMap<&str, &[u8]> {
+        self.1.iter()
+              .filter(|&(key, _)| key.starts_with(XATTR_PREFIX))
+              .map(|(key, val)| (&key[XATTR_PREFIX.len()..], &val[..]))
+              .collect()
+    }
+    pub fn set_xattr(&mut self, name: &str, value: &[u8]) {
+        self.1.insert(format!("{}{}", XATTR_PREFIX, name), value.to_vec());
+    }
+    // Set the path, falling back to a PAX record if it won't fit in the header
+    pub fn set_path(&mut self, path: &Path) {
+        if self.1.contains_key("GNU.sparse.name") {
+            self.1.insert("GNU.sparse.name".to_owned(), path.as_os_str().as_bytes().to_vec());
+            return
+        }
+        self.1.remove("path");
+        if self.0.set_path(path).is_err() {
+            self.1.insert("path".to_owned(), path.as_os_str().as_bytes().to_vec());
+            let name = truncated_name(path);
+            self.0.set_path(&name).unwrap();
+        }
+        self.0.set_cksum();
+    }
+    pub fn set_link_name(&mut self, linkname: &Path) {
+        self.1.remove("linkpath");
+        if self.0.set_link_name(linkname).is_err() {
+            self.1.insert("linkpath".to_owned(), linkname.as_os_str().as_bytes().to_vec());
+            let name = truncated_name(linkname);
+            self.0.set_link_name(&name).unwrap();
+        }
+        self.0.set_cksum();
+    }
+    // Clear out timestamps, for when they're not being compared
+    pub fn clear_times(&mut self) {
+        self.0.set_mtime(0);
+        for key in &["mtime", "atime", "ctime"] {
+            self.1.remove(*key);
+        }
+        // Old GNU headers have atime and ctime where the ustar prefix would be
+        if &self.0.ustar[..] == &b"ustar "[..] {
+            for b in &mut head_bytes_mut(&mut self.0)[GNU_ATIME_OFFSET..GNU_ATIME_OFFSET + 24] {
+                *b = 0
+            }
+        }
+        self.0.set_cksum();
+    }
+    // Clear out ownership, for when it's not being compared
+    pub fn clear_owner(&mut self) {
+        self.0.set_uid(0);
+        self.0.set_gid(0);
+        self.0.owner_name = [0; 32];
+        self.0.group_name = [0; 32];
+        for key in &["uid", "gid", "uname", "gname"] {
+            self.1.remove(*key);
+        }
+        self.0.set_cksum();
+    }
+    // Reset the mode to the usual one, for when it's not being compared
+    pub fn clear_mode(&mut self) {
+        self.0.set_mode(if self.is_dir() { 0o755 } else { 0o644 });
+        self.0.set_cksum();
+    }
+    // Clear out extended attributes, for when they're not being compared
+    pub fn clear_xattrs(&mut self) {
+        let keys: Vec<String> = self.1.keys().filter(|key| key.starts_with(XATTR_PREFIX)).cloned().collect();
+        for key in keys {
+            self.1.remove(&key);
+        }
+    }
+    pub fn is_hard_link(&self) -> bool {
+        self.0.link[0] == b'1'
+    }
+    fn file_name(&self) -> Option<String> {
+        self.path().file_name().and_then(|name| name.to_str()).map(|name| name.to_owned())
+    }
+    pub fn is_whiteout(&self) -> bool {
+        self.file_name().map_or(false, |name| name.starts_with(WHITEOUT_PREFIX))
+    }
+    // The directory an opaque whiteout hides the lower contents of
+    pub fn opaque_dir(&self) -> Option<PathBuf> {
+        if self.file_name().map_or(false, |name| name == OPAQUE_WHITEOUT) {
+            Some(normpath(&self.path()).parent().unwrap().to_path_buf())
+        } else {
+            None
+        }
+    }
+    // The path a (non-opaque) whiteout hides
+    pub fn whiteout_target(&self) -> Option<PathBuf> {
+        if !self.is_whiteout() || self.opaque_dir().is_some() {
+            return None
+        }
+        let path = normpath(&self.path());
+        let name = self.file_name().unwrap();
+        Some(path.with_file_name(&name[WHITEOUT_PREFIX.len()..]))
+    }
+    // Names of the fields that differ from another header, to explain why two
+    // headers for the same path aren't equal. Headers which only differ in how
+    // they're encoded (e.g. ustar vs GNU format) are put down to "format".
+    pub fn differences(&self, other: &HashableHeader) -> Vec<&'static str> {
+        let mut fields = vec![];
+        {
+            let mut check = |field, differs| if differs { fields.push(field) };
+            check("path", self.path() != other.path());
+            check("type", self.0.link[0] != other.0.link[0]);
+            check("mode", self.0.mode().unwrap() != other.0.mode().unwrap());
+            check("uid", self.uid() != other.uid());
+            check("gid", self.gid() != other.gid());
+            check("uname", self.uname() != other.uname());
+            check("gname", self.gname() != other.gname());
+            check("mtime", self.mtime() != other.mtime());
+            check("atime", self.1.get("atime") != other.1.get("atime"));
+            check("ctime", self.1.get("ctime") != other.1.get("ctime"));
+            check("size", self.size() != other.size());
+            check("linkname", self.link_name() != other.link_name());
+            check("device", self.0.dev_major != other.0.dev_major || self.0.dev_minor != other.0.dev_minor);
+            check("xattrs", self.xattrs() != other.xattrs());
+            check("sparse", self.is_sparse() != other.is_sparse() || self.real_size() != other.real_size() ||
+                            self.sparse_map() != other.sparse_map());
+        }
+        if fields.is_empty() && self != other {
+            fields.push("format")
+        }
+        fields
+    }
+    fn head_bytes(&self) -> &[u8; 512] {
+        head_bytes(&self.0)
+    }
+}
+impl Hash for HashableHeader {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.head_bytes().hash(state);
+        self.1.hash(state);
+        self.2.hash(state);
+    }
+}
+impl PartialEq for HashableHeader {
+    fn eq(&self, other: &HashableHeader) -> bool {
+        self.head_bytes()[..] == other.head_bytes()[..] && self.1 == other.1 && self.2 == other.2
+    }
+}
+impl Eq for HashableHeader {}
+
+// stolen from tar-rs
+fn head_bytes(header: &tar::Header) -> &[u8; 512] {
+    unsafe { &*(header as *const _ as *const [u8; 512]) }
+}
+fn head_bytes_mut(header: &mut tar::Header) -> &mut [u8; 512] {
+    unsafe { &mut *(header as *mut _ as *mut [u8; 512]) }
+}
+
+// Normalise it https://github.com/rust-lang/rust/issues/29008
+pub fn normpath(path: &Path) -> PathBuf {
+    path.components().as_path().to_path_buf()
+}
+
+// Directories above a (normalised) path, nearest first
+pub fn parent_dirs(path: &Path) -> Vec<PathBuf> {
+    let mut parents = vec![];
+    let mut parent = path.parent();
+    while let Some(dir) = parent {
+        if dir == Path::new("") {
+            break
+        }
+        parents.push(dir.to_path_buf());
+        parent = dir.parent();
+    }
+    parents
+}
+
+// Something that fits in the header name field, for use when the real path
+// lives in an extended header
+fn truncated_name(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap().as_bytes();
+    PathBuf::from(OsStr::from_bytes(&name[..cmp::min(name.len(), 99)]))
+}
+
+// Old GNU format header fields for sparse files, living where the ustar prefix
+// would be
+const GNU_ATIME_OFFSET: usize = 345;
+const GNU_SPARSE_OFFSET: usize = 386;
+const GNU_ISEXTENDED_OFFSET: usize = 482;
+const GNU_REALSIZE_OFFSET: usize = 483;
+// ...and in the extension blocks following the header
+const GNU_EXT_ISEXTENDED_OFFSET: usize = 504;
+
+fn parse_sparse_entries(slice: &[u8]) -> Vec<(u64, u64)> {
+    slice.chunks(24)
+         .map(|entry| (numeric_from(&entry[..12]), numeric_from(&entry[12..])))
+         .take_while(|&(offset, numbytes)| offset != 0 || numbytes != 0)
+         .collect()
+}
+
+// octal_from in tar-rs, but also understanding the GNU base-256 extension for
+// large numbers
+fn numeric_from(slice: &[u8]) -> u64 {
+    if slice[0] & 0x80 != 0 {
+        return slice[1..].iter().fold((slice[0] & 0x7f) as u64, |num, b| (num << 8) | *b as u64)
+    }
+    let num = str::from_utf8(truncate(slice)).unwrap().trim_matches(|c| c == ' ' || c == '\0');
+    if num.is_empty() {
+        return 0
+    }
+    u64::from_str_radix(num, 8).unwrap()
+}
+
+// octal_from in tar-rs
+fn truncate(slice: &[u8]) -> &[u8] {
+    match slice.iter().position(|i| *i == 0) {
+        Some(i) => &slice[..i],
+        None => slice,
+    }
+}
+fn decimal_from(slice: &[u8]) -> io::Result<u64> {
+    let num = match str::from_utf8(truncate(slice)) {
+        Ok(n) => n,
+        Err(_) => panic!("noo"),
+    };
+    match u64::from_str_radix(num.trim(), 10) {
+        Ok(n) => Ok(n),
+        Err(_) => panic!("noo"),
+    }
+}
+pub fn parse_extended_header_data(extended_header: &[u8]) -> PaxRecords {
+    let mut data = extended_header;
+    let mut outmap = PaxRecords::new();
+    // PAX format 0.0 sparse files repeat these keys for each chunk
+    let mut sparsechunks: Vec<String> = vec![];
+    while data.len() != 0 {
+        let spacepos: usize = data.iter().position(|c| *c == b' ').unwrap();
+        let (sizeslice, restdata) = data.split_at(spacepos);
+        let size = decimal_from(sizeslice).unwrap();
+        let (spacekvslice, restdata2) = restdata.split_at(size as usize - sizeslice.len());
+        let kvslice = &spacekvslice[1..spacekvslice.len() - 1];
+        let eqpos: usize = kvslice.iter().position(|c| *c == b'=').unwrap();
+        let (key, eqval) = kvslice.split_at(eqpos);
+        let val = &eqval[1..];
+        data = restdata2;
+        if key == &b"GNU.sparse.offset"[..] || key == &b"GNU.sparse.numbytes"[..] {
+            sparsechunks.push(str::from_utf8(val).unwrap().to_owned());
+            continue
+        }
+        assert!(outmap.insert(str::from_utf8(key).unwrap().to_owned(), val.to_vec())
+                      .is_none());
+    }
+    // Turn it into the equivalent format 0.1 map, which can be written out
+    // again without repeated keys
+    if !sparsechunks.is_empty() {
+        assert!(outmap.insert("GNU.sparse.map".to_owned(), sparsechunks.join(",").into_bytes())
+                      .is_none());
+    }
+    outmap
+}
+pub fn make_extended_header_data(records: &PaxRecords) -> Vec<u8> {
+    let mut data = vec![];
+    for (key, val) in records {
+        // The length includes the digits of the length itself
+        let baselen = key.len() + val.len() + 3; // space, equals, newline
+        let mut len = baselen + baselen.to_string().len();
+        if len.to_string().len() != baselen.to_string().len() {
+            len = baselen + len.to_string().len()
+        }
+        data.extend_from_slice(format!("{} {}=", len, key).as_bytes());
+        data.extend_from_slice(val);
+        data.push(b'\n');
+    }
+    data
+}
+
+// Apply PAX records on top of existing ones - an empty value removes any
+// earlier value (e.g. from a global header) so the header field applies again
+fn merge_records(base: &mut PaxRecords, records: PaxRecords) {
+    for (key, val) in records {
+        // Comments are for humans (git archive puts the commit id in one) and
+        // have no effect on the entry
+        if key == "comment" {
+            continue
+        }
+        if val.is_empty() {
+            base.remove(&key);
+        } else {
+            base.insert(key, val);
+        }
+    }
+}
+
+fn read_extended_header_data<R: Read>(reader: &mut R, datasize: u64) -> PaxRecords {
+    let mut extdata = vec![];
+    (&mut *reader).take(datasize).read_to_end(&mut extdata).unwrap();
+    assert!(extdata.len() as u64 == datasize);
+    skip(reader, round_up_block(datasize) - datasize);
+    parse_extended_header_data(&extdata)
+}
+
+fn round_up_block(size: u64) -> u64 {
+    (size + 511) / 512 * 512
+}
+
+// Read past data rather than seeking so any reader will do
+fn skip<R: Read>(reader: &mut R, size: u64) {
+    let skipped = io::copy(&mut (&mut *reader).take(size), &mut io::sink()).unwrap();
+    assert!(skipped == size, "archive truncated");
+}
+
+// tar-rs knows nothing about extended headers and so can't be trusted to find
+// the data (e.g. a PAX size record changes where the next header is), so walk
+// the archive ourselves. The archive is read once from start to end, with f
+// given each entry, the offset of its data and a reader for the data - any data
+// it doesn't read is skipped over.
+// Global extended header records are folded into every entry they apply to,
+// so they're part of the comparison and get written out alongside each entry.
+// GNU long names are treated as PAX path records in the same way.
+// http://stackoverflow.com/questions/2078778/what-exactly-is-the-gnu-tar-longlink-trick
+// https://golang.org/pkg/archive/tar/
+pub fn walk_layer_entries<R, F>(reader: &mut R, mut f: F)
+    where R: Read, F: FnMut(HashableHeader, u64, &mut Read)
+{
+    let mut offset = 0;
+    let mut globals = PaxRecords::new();
+    let mut pending: Option<PaxRecords> = None;
+    let mut gnulongnames = PaxRecords::new();
+    loop {
+        let mut header = tar::Header::new();
+        match reader.read_exact(head_bytes_mut(&mut header)) {
+            Ok(()) => (),
+            // Not all writers bother with the trailing zero blocks
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => panic!("failed to read header: {}", e),
+        }
+        if head_bytes(&header).iter().all(|b| *b == 0) {
+            break
+        }
+        assert!(header.cksum().unwrap() == header_cksum(&header),
+                "bad header checksum at offset {}", offset);
+        offset += 512;
+        let ftype = header.link[0];
+        // Solaris uses X for the same thing
+        if ftype == b'x' || ftype == b'X' {
+            assert!(pending.is_none(), "extended header followed by another extended header");
+            let datasize = numeric_from(&header.size);
+            pending = Some(read_extended_header_data(reader, datasize));
+            offset += round_up_block(datasize);
+            continue
+        } else if ftype == b'g' {
+            assert!(pending.is_none(), "extended header followed by a global extended header");
+            let datasize = numeric_from(&header.size);
+            let records = read_extended_header_data(reader, datasize);
+            merge_records(&mut globals, records);
+            offset += round_up_block(datasize);
+            continue
+        } else if ftype == b'L' || ftype == b'K' {
+            let datasize = numeric_from(&header.size);
+            let mut longname = vec![];
+            (&mut *reader).take(datasize).read_to_end(&mut longname).unwrap();
+            assert!(longname.len() as u64 == datasize);
+            skip(reader, round_up_block(datasize) - datasize);
+            let longname = truncate(&longname).to_vec();
+            let key = if ftype == b'L' { "path" } else { "linkpath" };
+            assert!(gnulongnames.insert(key.to_owned(), longname).is_none(),
+                    "repeated GNU long name header at offset {}", offset);
+            offset += round_up_block(datasize);
+            continue
+        } else if ftype == b'V' {
+            // Volume labels don't correspond to anything on the filesystem
+            let datasize = round_up_block(numeric_from(&header.size));
+            skip(reader, datasize);
+            offset += datasize;
+            continue
+        } else if ftype == b'M' || ftype == b'N' {
+            panic!("Unsupported GNU header (multi-volume or old long name): {}", ftype as char)
+        }
+        // Other vendor-specific types are treated as regular files, as POSIX
+        // requires - the data is carried across untouched
+        let mut sparseblocks = vec![];
+        if ftype == b'S' {
+            let mut isextended = head_bytes(&header)[GNU_ISEXTENDED_OFFSET] != 0;
+            while isextended {
+                let mut block = [0u8; 512];
+                reader.read_exact(&mut block).unwrap();
+                isextended = block[GNU_EXT_ISEXTENDED_OFFSET] != 0;
+                sparseblocks.extend_from_slice(&block);
+                offset += 512;
+            }
+        }
+        let mut records = globals.clone();
+        merge_records(&mut records, mem::replace(&mut gnulongnames, PaxRecords::new()));
+        if let Some(entryrecords) = pending.take() {
+            merge_records(&mut records, entryrecords)
+        }
+        let hheader = HashableHeader(header, records, sparseblocks);
+        let size = hheader.size();
+        {
+            let mut data = (&mut *reader).take(size);
+            f(hheader, offset, &mut data);
+            let remaining = data.limit();
+            skip(&mut data, remaining);
+        }
+        skip(reader, round_up_block(size) - size);
+        offset += round_up_block(size);
+    }
+}
+
+fn header_cksum(header: &tar::Header) -> u32 {
+    let bytes = head_bytes(header);
+    // The checksum field itself is treated as spaces
+    bytes[..148].iter().chain(&[b' '; 8]).chain(&bytes[156..])
+                .fold(0, |sum, b| sum + *b as u32)
+}
+
+// Write an entry, preceded by an extended header if it has any records and
+// followed by any sparse extension blocks
+pub fn append_entry<W: Write>(outar: &Archive<W>, hheader: &HashableHeader, data: &mut Read) {
+    if !hheader.1.is_empty() {
+        let extdata = make_extended_header_data(&hheader.1);
+        let mut extheader = tar::Header::new();
+        let extpath = Path::new("PaxHeaders.0").join(truncated_name(&hheader.path()));
+        if extheader.set_path(&extpath).is_err() {
+            extheader.set_path("PaxHeaders.0/entry").unwrap()
+        }
+        extheader.set_mode(0o644);
+        extheader.set_uid(0);
+        extheader.set_gid(0);
+        extheader.set_mtime(0);
+        extheader.set_size(extdata.len() as u64);
+        extheader.link[0] = b'x';
+        extheader.set_cksum();
+        outar.append(&extheader, &mut &extdata[..]).unwrap();
+    }
+    outar.append(&hheader.0, &mut (&hheader.2[..]).chain(data)).unwrap();
+}
@@ -9,473 +9,1870 @@
 #[cfg(test)]
 #[macro_use] extern crate lazy_static;
 
+extern crate crossbeam;
+extern crate crypto;
 extern crate docopt;
+extern crate flate2;
 extern crate env_logger;
 extern crate mime;
 extern crate reqwest;
 extern crate rustc_serialize;
 extern crate tar;
+extern crate zstd;
 
+mod auth;
+mod compress;
+mod diff;
+mod layer;
+mod overlay;
+mod rebase;
+mod reference;
+mod save;
+mod squash;
+mod tree;
 mod util;
 
-use std::collections::{HashMap, HashSet};
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env;
 use std::fs;
 use std::fs::File;
-use std::hash::{Hash, Hasher};
+use std::hash::Hash;
 use std::io;
 use std::io::{BufReader, BufWriter};
 use std::io::prelude::*;
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process;
+use std::process::{Command, Stdio};
 use std::str;
 
 use reqwest::Client;
+use reqwest::RequestBuilder;
 use reqwest::Response;
-use reqwest::header::{Accept, Authorization, Bearer, Headers, qitem};
+use reqwest::header::{Accept, Authorization, Basic, Bearer, Headers, qitem};
 use reqwest::Method;
 use mime::{Mime, TopLevel, SubLevel};
 use reqwest::StatusCode;
 use reqwest::Url;
 
 use rustc_serialize::json;
+use rustc_serialize::json::Json;
 
 use tar::Archive;
 
-use util::{find_common_keys, format_num_bytes, readers_identical, to_string_slices};
+use auth::Credentials;
+use compress::{Compression, LayerDigests, LayerReader, LayerWriter};
+use layer::{HashableHeader, append_entry, normpath, parent_dirs, walk_layer_entries};
+use overlay::overlay_layers;
+use reference::Reference;
+use save::{SaveAnalysis, SaveSource, json_array_mut, json_object_mut};
+use util::{DigestWriter, WorkDir, find_common_keys, format_num_bytes, parallel_map, sha256_hex, to_paths, to_string_slices};
 
-// https://github.com/rust-lang/rust/issues/13721
-#[derive(Clone)]
-struct HashableHeader(tar::Header);
-impl HashableHeader {
-    pub fn new(srcheader: &tar::Header) -> HashableHeader {
-        HashableHeader(srcheader.clone())
-    }
-    // stolen from tar-rs
-    fn head_bytes(&self) -> &[u8; 512] {
-        unsafe { &*(&self.0 as *const _ as *const [u8; 512]) }
-    }
+// Metadata that has to match for files to be considered the same with
+// --content-hash. Entry type, link target and device numbers always have to, as
+// they're part of the content.
+#[derive(Clone, Copy)]
+pub struct MatchFields {
+    pub mode: bool,
+    pub owner: bool,
+    pub xattrs: bool,
 }
-impl Hash for HashableHeader {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.head_bytes().hash(state);
+impl MatchFields {
+    pub fn parse(fields: &str) -> MatchFields {
+        let mut matchfields = MatchFields { mode: false, owner: false, xattrs: false };
+        for field in fields.split(',').filter(|f| !f.is_empty()) {
+            match field {
+                "mode" => matchfields.mode = true,
+                "owner" => matchfields.owner = true,
+                "xattrs" => matchfields.xattrs = true,
+                _ => panic!("unknown field to match on: {}", field),
+            }
+        }
+        matchfields
     }
 }
-impl PartialEq for HashableHeader {
-    fn eq(&self, other: &HashableHeader) -> bool {
-        self.head_bytes()[..] == other.head_bytes()[..]
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct ContentKey {
+    path: PathBuf,
+    digest: String,
+    // Always compared, holes are part of the content
+    sparse: Option<(u64, Option<Vec<(u64, u64)>>)>,
+    // Always compared too, a directory isn't an empty file and links and
+    // devices are only their target
+    ftype: u8,
+    link: Option<PathBuf>,
+    device: ([u8; 8], [u8; 8]),
+    // Only filled in for the fields being matched on
+    mode: Option<u32>,
+    owner: Option<(u64, u64)>,
+    xattrs: Option<Vec<(String, Vec<u8>)>>,
+}
+impl ContentKey {
+    fn new(hheader: &HashableHeader, digest: String, fields: &MatchFields) -> ContentKey {
+        let sparse = if hheader.is_sparse() {
+            Some((hheader.real_size(), hheader.sparse_map()))
+        } else {
+            None
+        };
+        ContentKey {
+            path: normpath(&hheader.path()),
+            digest: digest,
+            sparse: sparse,
+            ftype: hheader.0.link[0],
+            link: hheader.link_name().map(|p| normpath(&p)),
+            device: (hheader.0.dev_major, hheader.0.dev_minor),
+            mode: if fields.mode { Some(hheader.0.mode().unwrap()) } else { None },
+            owner: if fields.owner { Some((hheader.uid(), hheader.gid())) } else { None },
+            xattrs: if fields.xattrs {
+                Some(hheader.xattrs().into_iter().map(|(k, v)| (k.to_owned(), v.to_vec())).collect())
+            } else {
+                None
+            },
+        }
     }
 }
-impl Eq for HashableHeader {}
 
-// octal_from in tar-rs
-fn truncate(slice: &[u8]) -> &[u8] {
-    match slice.iter().position(|i| *i == 0) {
-        Some(i) => &slice[..i],
-        None => slice,
+pub struct CommoniseOptions {
+    pub outdir: PathBuf,
+    // Match on contents and selected metadata rather than exact headers
+    pub contenthash: Option<MatchFields>,
+    // Build a tree of shared layers rather than a single common layer
+    pub tree: bool,
+    // Number of tars to read or write at once
+    pub threads: usize,
+    pub commonname: String,
+    // Name for each individual layer, see individual_path
+    pub nametemplate: String,
+    // Overwrite existing output files
+    pub force: bool,
+    // Of the output layers, which get the appropriate extension added
+    pub compression: Compression,
+}
+impl Default for CommoniseOptions {
+    fn default() -> CommoniseOptions {
+        CommoniseOptions {
+            outdir: PathBuf::from("."),
+            contenthash: None,
+            tree: false,
+            threads: 4,
+            commonname: "common.tar".to_owned(),
+            nametemplate: "individual_{index}.tar".to_owned(),
+            force: false,
+            compression: Compression::None,
+        }
     }
 }
-fn decimal_from(slice: &[u8]) -> io::Result<u64> {
-    let num = match str::from_utf8(truncate(slice)) {
-        Ok(n) => n,
-        Err(_) => panic!("noo"),
-    };
-    match u64::from_str_radix(num.trim(), 10) {
-        Ok(n) => Ok(n),
-        Err(_) => panic!("noo"),
+impl CommoniseOptions {
+    pub fn output_path(&self, name: String) -> PathBuf {
+        self.outdir.join(name + self.compression.extension())
+    }
+    pub fn common_path(&self) -> PathBuf {
+        self.output_path(self.commonname.clone())
+    }
+    // Named after the common layer, so runs sharing an output directory don't
+    // clash
+    pub fn graph_path(&self) -> PathBuf {
+        let commonpath = Path::new(&self.commonname);
+        let stem = commonpath.file_stem().and_then(|s| s.to_str()).unwrap_or(&self.commonname);
+        self.outdir.join(format!("{}.layers.json", stem))
+    }
+    // The template can use {index} (position in the arguments), {stem} (the
+    // input filename without extension) and {dir} (the name of the directory
+    // holding the input, i.e. the layer id for a tar in a `docker save`)
+    pub fn individual_path(&self, index: usize, tname: &str) -> PathBuf {
+        let tpath = Path::new(tname);
+        let stem = tpath.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+        let dir = tpath.parent().and_then(|p| p.file_name()).and_then(|d| d.to_str()).unwrap_or("");
+        let name = self.nametemplate.replace("{index}", &index.to_string())
+                                    .replace("{stem}", stem)
+                                    .replace("{dir}", dir);
+        self.output_path(name)
     }
 }
-fn parse_extended_header_data(extended_header: &[u8]) -> HashMap<&str, &str> {
-    let mut data = extended_header;
-    let mut outmap: HashMap<&str, &str> = HashMap::new();
-    while data.len() != 0 {
-        let spacepos: usize = data.iter().position(|c| *c == b' ').unwrap();
-        let (sizeslice, restdata) = data.split_at(spacepos);
-        let size = decimal_from(sizeslice).unwrap();
-        let (spacekvslice, restdata2) = restdata.split_at(size as usize - sizeslice.len());
-        let kvslice = &spacekvslice[1..spacekvslice.len() - 1];
-        let eqpos: usize = kvslice.iter().position(|c| *c == b'=').unwrap();
-        let (key, eqval) = kvslice.split_at(eqpos);
-        let val = &eqval[1..];
-        assert!(outmap.insert(str::from_utf8(key).unwrap(), str::from_utf8(val).unwrap())
-                      .is_none());
-        data = restdata2
+
+// Where a path really is, for one that may not exist yet (but whose directory
+// does) - None if it can't be anywhere
+fn canonical_path(path: &Path) -> Option<PathBuf> {
+    if let Ok(path) = fs::canonicalize(path) {
+        return Some(path)
+    }
+    let dir = match path.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+    match (fs::canonicalize(dir), path.file_name()) {
+        (Ok(dir), Some(name)) => Some(dir.join(name)),
+        _ => None,
     }
-    outmap
 }
 
-fn get_header_map<'a, 'b>(arfiles: &'a mut Vec<tar::Entry<'b, fs::File>>)
-                          -> HashMap<HashableHeader, &'a mut tar::Entry<'b, fs::File>> {
-    let mut arfilemap: HashMap<HashableHeader, &'a mut tar::Entry<'b, _>> = HashMap::new();
-    for file in arfiles.iter_mut() {
-        arfilemap.insert(HashableHeader::new(file.header()), file);
+// Make sure we're not about to clobber anything, including our own outputs
+// and, even with --force, what we're reading from
+fn check_outputs(paths: &[&Path], inputs: &[&Path], force: bool) {
+    let inputs: HashSet<PathBuf> = inputs.iter().filter_map(|path| canonical_path(path)).collect();
+    let mut seen = HashSet::new();
+    for path in paths {
+        assert!(seen.insert(path), "{} would be written more than once", path.display());
+        assert!(!canonical_path(path).map_or(false, |p| inputs.contains(&p)),
+                "{} is an input, so can't also be an output", path.display());
+        assert!(force || !path.exists(), "{} already exists, use --force to overwrite", path.display());
     }
-    arfilemap
 }
 
 fn make_layer_tar<'a,
-                  'b: 'a,
-                  I1: Iterator<Item = &'a HashableHeader>,
-                  I2: Iterator<Item = &'a mut tar::Entry<'b, fs::File>>,
-                  F: Fn(&Path) -> tar::Header>
-    (outname: &str,
-     headeriter: I1,
-     verbatimiter: I2,
-     headertofilemap: &mut HashMap<HashableHeader, &mut tar::Entry<fs::File>>,
-     mkdir: F) {
-
-    let outfile = fs::File::create(outname).unwrap();
-    let outar = Archive::new(outfile);
-
-    // Alphabetical ordering, lets us make assumptions about directory traversal
-    let mut headers: Vec<&HashableHeader> = headeriter.collect();
-    headers.sort_by(|h1, h2| h1.0.path_bytes().cmp(&h2.0.path_bytes()));
+                  I: Iterator<Item = &'a HashableHeader>,
+                  F: Fn(&Path) -> Option<HashableHeader>,
+                  N: Fn(&HashableHeader) -> HashableHeader>
+    (outpath: &Path,
+     compression: Compression,
+     headeriter: I,
+     archive: &mut LayerReader,
+     index: &HashMap<HashableHeader, EntryInfo>,
+     mkdir: F,
+     normalise: N)
+     -> LayerDigests {
+
+    let outar = Archive::new(LayerWriter::create(outpath, compression));
 
-    let mut lastdir = PathBuf::new();
-    // TODO: set trailing slash of dirs for belt and braces?
-    for hheader in &headers {
+    // Keep the order of the input so it's read front to back, which also
+    // means hard links still come after the file they point to
+    let mut headers: Vec<&HashableHeader> = headeriter.collect();
+    headers.sort_by_key(|h| index[*h].offset);
+    // Directories in this layer are written as soon as anything inside them
+    // is, unless they have data that would need reading out of order
+    let layerdirs: HashMap<PathBuf, &HashableHeader> = headers.iter()
+                                                              .filter(|h| h.is_dir() && h.size() == 0)
+                                                              .map(|h| (normpath(&h.path()), *h))
+                                                              .collect();
+    let mut writtendirs: HashSet<PathBuf> = HashSet::new();
+    for hheader in headers {
         let header = &hheader.0;
         assert!(&header.ustar[..5] == b"ustar"); // TODO: get this as public?
-        let path = header.path().unwrap();
-        // Climb up to find common prefix
-        while !path.starts_with(&lastdir) {
-            lastdir = lastdir.parent().unwrap().to_path_buf();
-        }
-        // Climb down creating dirs as necessary
-        let relpath = path.parent().unwrap().strip_prefix(&lastdir).unwrap().to_path_buf();
-        for relcomponent in relpath.iter() {
-            lastdir.push(relcomponent);
-            let newdir = mkdir(&lastdir);
-            outar.append(&newdir, &mut io::empty()).unwrap();
+        let path = normpath(&hheader.path());
+        // Create parent dirs as necessary
+        for dir in parent_dirs(&path).into_iter().rev() {
+            if !writtendirs.insert(dir.clone()) {
+                continue
+            }
+            let newdir = match layerdirs.get(&dir) {
+                Some(dirheader) => normalise(dirheader),
+                None => match mkdir(&dir) {
+                    Some(newdir) => newdir,
+                    // Already as it should be in the layer beneath
+                    None => continue,
+                },
+            };
+            append_entry(&outar, &newdir, &mut io::empty());
         }
-        let file = headertofilemap.get_mut(&hheader).unwrap();
-        outar.append(&header, file).unwrap();
-        file.seek(io::SeekFrom::Start(0)).unwrap();
-        if header.link[0] == b'5' {
-            lastdir = path.to_path_buf();
+        if hheader.is_dir() && !writtendirs.insert(path.clone()) && layerdirs.contains_key(&path) {
+            continue
         }
-    }
-    for af in verbatimiter {
-        let hheader = HashableHeader::new(af.header()).clone();
-        outar.append(&hheader.0, af).unwrap();
-        af.seek(io::SeekFrom::Start(0)).unwrap();
+        let outheader = normalise(hheader);
+        archive.skip_to(index[hheader].offset);
+        append_entry(&outar, &outheader, &mut (&mut *archive).take(outheader.size()));
     }
 
     outar.finish().unwrap();
+    outar.into_inner().finish()
 }
 
-fn get_archive_entries<'a>(ar: &'a Archive<fs::File>,
-                           tname: &str)
-                           -> (Vec<tar::Entry<'a, fs::File>>, Vec<tar::Entry<'a, fs::File>>) {
-    println!("Loading {}", tname);
-    let mut ignoredfiles: Vec<tar::Entry<_>> = vec![];
-    // Can't handle extended headers at the moment - skip the next block if
-    // prefixed by an extended header
-    let mut skipnext = false;
-    let mut extpath = PathBuf::new();
-    let emptypath = PathBuf::new();
-    // If we've skipped directories because of an extended header, exclude
-    // anything under that
-    let mut skipdirs: HashSet<PathBuf> = HashSet::new();
-    let arfiles: Vec<_> = ar.entries().unwrap().filter_map(|res| {
-        let af = res.unwrap();
-        let ftype = af.header().link[0];
-        // Handle extended headers, skip other headers if necessary
-        if ftype == b'g' {
-            panic!("Cannot handle global extended header")
-        } else if ftype == b'x' {
-            assert!(!skipnext && extpath == emptypath);
-            skipnext = true;
-            let mut extdata = vec![];
-            unsafe { // TODO: just to dodge mutability requirement
-                let afm = &mut *(&af as *const tar::Entry<fs::File> as *mut tar::Entry<fs::File>);
-                afm.read_to_end(&mut extdata).unwrap();
-                afm.seek(io::SeekFrom::Start(0)).unwrap();
-            }
-            let extheadmap = parse_extended_header_data(&extdata);
-            if extheadmap.contains_key("path") {
-                extpath = PathBuf::from(extheadmap["path"]);
-            }
-            ignoredfiles.push(af);
-            None
-        // http://stackoverflow.com/questions/2078778/what-exactly-is-the-gnu-tar-longlink-trick
-        // https://golang.org/pkg/archive/tar/
-        } else if b'A' <= ftype && ftype <= b'Z' {
-            panic!("Unknown vendor-specific header: {}", ftype as char)
-        } else if skipnext {
-            if ftype == b'5' { // dir
-                let headpath = af.header().path().unwrap().to_path_buf();
-                assert!(
-                    ((headpath == emptypath) ^ (extpath == emptypath)) ||
-                    extpath.to_str().unwrap().starts_with(headpath.to_str().unwrap())
-                );
-                let path = if extpath != emptypath { &extpath } else { &headpath };
-                // Normalise it https://github.com/rust-lang/rust/issues/29008
-                skipdirs.insert(path.components().as_path().to_path_buf());
-            }
-            skipnext = false;
-            extpath = emptypath.clone();
-            ignoredfiles.push(af);
-            None
-        } else {
-            // Does the path need to be skipped because a parent is skipped?
-            {
-                let path = af.header().path().unwrap().to_path_buf();
-                assert!(path != emptypath);
-                let mut prefix = path.parent();
-                while prefix != None {
-                    let p = prefix.unwrap();
-                    if skipdirs.contains(p) {
-                        ignoredfiles.push(af);
-                        return None
-                    }
-                    prefix = p.parent();
+// Group hard links with the file they point to, keyed by the path of that file
+// and ordered by path so groups can be compared between tars. Entries not
+// involved in hard links are left out.
+fn get_link_groups<'a, I: Iterator<Item = &'a HashableHeader>>(headers: I)
+                                                               -> HashMap<PathBuf, Vec<&'a HashableHeader>> {
+    let headers: Vec<&HashableHeader> = headers.collect();
+    let links: HashMap<PathBuf, PathBuf> = headers.iter()
+                                                  .filter(|h| h.is_hard_link())
+                                                  .map(|h| (normpath(&h.path()), normpath(&h.link_name().unwrap())))
+                                                  .collect();
+    let mut groups: HashMap<PathBuf, Vec<&HashableHeader>> = HashMap::new();
+    for hheader in headers.iter().filter(|h| h.is_hard_link()) {
+        // Links can point at other links
+        let mut root = normpath(&hheader.path());
+        let mut numhops = 0;
+        while let Some(target) = links.get(&root) {
+            root = target.clone();
+            numhops += 1;
+            assert!(numhops <= links.len(), "hard link loop at {}", hheader.path().display());
+        }
+        groups.entry(root).or_insert_with(Vec::new).push(*hheader);
+    }
+    for hheader in headers.iter().filter(|h| !h.is_hard_link()) {
+        if let Some(group) = groups.get_mut(&normpath(&hheader.path())) {
+            group.push(*hheader)
+        }
+    }
+    for group in groups.values_mut() {
+        group.sort_by(|h1, h2| h1.path().cmp(&h2.path()));
+    }
+    groups
+}
+
+// Hard links and the file they point to must end up in the same tar, so drop
+// any header whose link group isn't entirely common and identical in every tar.
+// Headers are compared with the key for the tar they came from, commonheaders
+// are from the first tar.
+fn filter_link_groups<K, F>(commonheaders: Vec<HashableHeader>,
+                            arlinkgroups: &[HashMap<PathBuf, Vec<&HashableHeader>>],
+                            tokey: F)
+                            -> Vec<HashableHeader>
+    where K: Eq + Hash, F: Fn(usize, &HashableHeader) -> K
+{
+    let mut splitpaths: HashSet<PathBuf> = HashSet::new();
+    {
+        let commonkeys: HashSet<K> = commonheaders.iter().map(|h| tokey(0, h)).collect();
+        let groupkeys = |i: usize, group: &[&HashableHeader]| -> Vec<K> {
+            group.iter().map(|h| tokey(i, h)).collect()
+        };
+        for (i, linkgroups) in arlinkgroups.iter().enumerate() {
+            for (root, group) in linkgroups {
+                let keys = groupkeys(i, group);
+                let iscommon = keys.iter().all(|k| commonkeys.contains(k)) &&
+                               arlinkgroups.iter().enumerate().all(|(j, lgs)| {
+                                   lgs.get(root).map_or(false, |g| groupkeys(j, g) == keys)
+                               });
+                if !iscommon {
+                    splitpaths.extend(group.iter().map(|h| normpath(&h.path())))
                 }
             }
-            Some(af)
         }
-    }).collect();
-    println!("Loading {}: found {} files, ignored {}",
-             tname,
-             arfiles.len(),
-             ignoredfiles.len());
-    (arfiles, ignoredfiles)
+    }
+    commonheaders.into_iter().filter(|h| !splitpaths.contains(&normpath(&h.path()))).collect()
+}
+
+// Where an entry's data is in its tar and what it hashes to
+#[derive(Clone)]
+pub struct EntryInfo {
+    pub offset: u64,
+    pub digest: String,
+}
+
+// Read through a tar once, recording everything needed about each entry
+fn index_archive(tname: &str, verbose: bool) -> HashMap<HashableHeader, EntryInfo> {
+    if verbose {
+        println!("Loading {}", tname)
+    }
+    let mut index = HashMap::new();
+    let mut numextended = 0;
+    walk_layer_entries(&mut LayerReader::open(Path::new(tname)), |hheader, offset, data| {
+        if !hheader.1.is_empty() {
+            numextended += 1
+        }
+        let info = EntryInfo { offset: offset, digest: sha256_hex(data) };
+        index.insert(hheader, info);
+    });
+    if verbose {
+        println!("Loading {}: found {} files, {} with extended headers",
+                 tname,
+                 index.len(),
+                 numextended)
+    }
+    index
 }
 
 // TODO
 // - check ustar at beginning
 // - check paths are not absolute
-// - be more intelligent about dirs - no point storing one child dir in common
-//   tar because we have to store the parents as well, and then have to
-//   overwrite the parents in specific tar
-// - implement rebasing 'onto' an image, with deletes for irrelevant files etc
 // - how do directory overwrites work in docker layers? e.g. if you chmod it,
 //   presumably it will pull parent directories up from the previous layer, does
 //   it grab children files as well?
 // - assert not more than one of the same name
-// - report files missed because of extended headers
 // - assert sane sequence of headers (x is followed by a normal file)
-// - handle extended headers
 // - assert it's a posix archives (i.e. dirs use type 5 rather than 1)
-// - ensure hard links don't get split across archives
 
 //       dayer export-image <imagetar>
 docopt!(Args derive Debug, "
 Usage:
-       dayer commonise-tar <tarpath> <tarpath> [<tarpath>...]
-       dayer download-image <imageurl> <targetdir>
+       dayer analyse-save [--json] <savepath>
+       dayer commonise-tar [--content-hash --match=<fields> --tree --threads=<n> --outdir=<dir> --common-name=<name> --name=<template> --force --compress=<format> --verify] <tarpath> <tarpath> [<tarpath>...]
+       dayer commonise-tar --dry-run [--json --content-hash --match=<fields> --threads=<n>] <tarpath> <tarpath> [<tarpath>...]
+       dayer commonise-image [--content-hash --match=<fields> --threads=<n> --force] <savepath> <outpath>
+       dayer verify-commonise [--content-hash --match=<fields> --threads=<n> --outdir=<dir> --common-name=<name>] <tarpath> <tarpath> [<tarpath>...]
+       dayer rebase [--from=<tarpaths> --force --compress=<format>] --onto=<tarpaths> <outpath> (<tarpath>... | --image=<name> <savepath>)
+       dayer squash [--force --compress=<format>] <outpath> (<tarpath>... | --image=<name> <savepath>)
+       dayer diff-layer [--force --compress=<format>] <old> <new> <outpath>
+       dayer download-image [--platform=<platform>] [--username=<user> --password-stdin] <image> <targetdir>
        dayer --help
 
 Options:
     --help     Show this message.
-    <imageurl> A fully qualified image url (e.g. `ubuntu` would be specified as
-               `https://registry-1.docker.io/library/ubuntu:latest`)
-");
+    --json     Print the analysis or dry run report as json rather than text.
+    --content-hash  Consider files the same if their path and contents match,
+               ignoring timestamps and any metadata not listed in --match.
+               Ignored metadata is normalised in the common layer.
+    --match=<fields>  Metadata that must match with --content-hash, from
+               mode, owner and xattrs. [default: mode,owner,xattrs]
+    --tree     Build a tree of layers shared by subsets of the tars, rather
+               than only sharing files common to all of them.
+    --threads=<n>  Number of tars to read or write at once. [default: 4]
+    --outdir=<dir>  Directory to write commonise-tar output into. [default: .]
+    --common-name=<name>  Filename of the common layer. [default: common.tar]
+    --name=<template>  Filename of each individual layer, where {index} is the
+               position of the input tar in the arguments, {stem} is its
+               filename without extension and {dir} is the name of the
+               directory it's in. [default: individual_{index}.tar]
+    --force    Overwrite existing output files (never inputs).
+    --dry-run  Report what commonise-tar would share, and paths in every tar
+               that weren't shared, without writing anything.
+    --verify   Check the output of commonise-tar recreates each input tar, as
+               verify-commonise does.
+    --onto=<tarpaths>  Layer tars of the base image to rebase onto, bottom
+               first and separated by commas.
+    --from=<tarpaths>  Layer tars of the base image being rebased from, bottom
+               first and separated by commas. Anything the image didn't
+               change from this base is left to the new one.
+    --image=<name>  Image in the `docker save` to squash or rebase, as repo:tag
+               or the id of its top layer.
+    --compress=<format>  Compress output layers with gzip or zstd - the outpath
+               of rebase, squash and diff-layer, or commonise-tar's layers,
+               whose names get .gz or .zst added. [default: none]
+    --platform=<platform>  Platform to download when an image is built for
+               several, as os/arch or os/arch/variant. Defaults to this
+               machine's.
+    --username=<user>  Log in to the registry as this user, rather than with
+               credentials saved by `docker login`.
+    --password-stdin  Read the password for --username from stdin.
+    <savepath> Output of `docker save`, either the tarball or a directory it
+               has been extracted into.
+    <outpath>  Where to write the tarball of commonised images, suitable for
+               `docker load`.
+    <old>, <new>  Filesystems to diff, each a directory or a tar of one.
+    <image>    An image reference like `ubuntu`, `quay.io/coreos/etcd:v3.1` or
+               `localhost:5000/app@sha256:<hex>`, or a url like
+               `https://registry-1.docker.io/library/ubuntu:latest`.
+", flag_threads: usize);
 
 fn main() {
     let args: Args = Args::docopt().decode().unwrap_or_else(|e| e.exit());
-    if args.cmd_commonise_tar {
-        commonise_tars(&to_string_slices(&args.arg_tarpath))
+    if args.flag_threads == 0 {
+        docopt::Error::Argv("--threads must be at least 1".to_owned()).exit()
+    }
+    if args.cmd_analyse_save {
+        analyse_save(&args.arg_savepath, args.flag_json)
+    } else if args.cmd_commonise_tar {
+        let opts = commonise_options(&args);
+        let tnames = to_string_slices(&args.arg_tarpath);
+        if args.flag_dry_run {
+            let report = report_commonise(&tnames, &opts, !args.flag_json);
+            if args.flag_json {
+                println!("{}", json::encode(&report).unwrap())
+            } else {
+                print_report(&report)
+            }
+            return
+        }
+        commonise_tars(&tnames, &opts);
+        if args.flag_verify && !verify_commonise(&tnames, &opts).is_empty() {
+            process::exit(1)
+        }
+    } else if args.cmd_verify_commonise {
+        let opts = commonise_options(&args);
+        if !verify_commonise(&to_string_slices(&args.arg_tarpath), &opts).is_empty() {
+            process::exit(1)
+        }
+    } else if args.cmd_commonise_image {
+        let opts = commonise_options(&args);
+        commonise_image(&args.arg_savepath, &args.arg_outpath, opts)
+    } else if args.cmd_rebase {
+        let outpath = Path::new(&args.arg_outpath);
+        let fromnames: Vec<&str> = args.flag_from.split(',').filter(|name| !name.is_empty()).collect();
+        let ontonames: Vec<&str> = args.flag_onto.split(',').collect();
+        let mut inputs = if args.flag_image.is_empty() {
+            to_paths(&args.arg_tarpath)
+        } else {
+            vec![Path::new(&args.arg_savepath)]
+        };
+        inputs.extend(fromnames.iter().chain(&ontonames).map(Path::new));
+        check_outputs(&[outpath], &inputs, args.flag_force);
+        let fromnames = if fromnames.is_empty() { None } else { Some(&fromnames[..]) };
+        let compression = Compression::parse(&args.flag_compress);
+        let digests = if args.flag_image.is_empty() {
+            rebase::rebase_layers(&to_string_slices(&args.arg_tarpath), fromnames, &ontonames, outpath, compression)
+        } else {
+            with_image_layers(&args.arg_savepath, &args.flag_image, outpath, |layerpaths| {
+                let tnames: Vec<&str> = layerpaths.iter().map(|path| path.to_str().unwrap()).collect();
+                rebase::rebase_layers(&tnames, fromnames, &ontonames, outpath, compression)
+            })
+        };
+        println!("Created {} ({})", outpath.display(), digests.digest);
+    } else if args.cmd_squash {
+        let outpath = Path::new(&args.arg_outpath);
+        let inputs = if args.flag_image.is_empty() {
+            to_paths(&args.arg_tarpath)
+        } else {
+            vec![Path::new(&args.arg_savepath)]
+        };
+        check_outputs(&[outpath], &inputs, args.flag_force);
+        let compression = Compression::parse(&args.flag_compress);
+        let digests = if args.flag_image.is_empty() {
+            squash::squash_layers(&args.arg_tarpath, outpath, compression)
+        } else {
+            with_image_layers(&args.arg_savepath, &args.flag_image, outpath, |layerpaths| {
+                squash::squash_layers(layerpaths, outpath, compression)
+            })
+        };
+        println!("Created {} ({})", outpath.display(), digests.digest);
+    } else if args.cmd_diff_layer {
+        let outpath = Path::new(&args.arg_outpath);
+        check_outputs(&[outpath], &[Path::new(&args.arg_old), Path::new(&args.arg_new)], args.flag_force);
+        let digests = diff::diff_layer(Path::new(&args.arg_old),
+                                       Path::new(&args.arg_new),
+                                       outpath,
+                                       Compression::parse(&args.flag_compress));
+        println!("Created {} ({})", outpath.display(), digests.digest);
     } else if args.cmd_download_image {
-        download_image(&args.arg_imageurl, &args.arg_targetdir)
+        let platform = if args.flag_platform.is_empty() {
+            Platform::host()
+        } else {
+            Platform::parse(&args.flag_platform)
+        };
+        let image = Reference::parse(&args.arg_image);
+        let creds = if !args.flag_username.is_empty() {
+            assert!(args.flag_password_stdin, "--username needs --password-stdin");
+            let mut password = String::new();
+            io::stdin().read_to_string(&mut password).unwrap();
+            Some(Credentials::Password {
+                username: args.flag_username.clone(),
+                password: password.trim_right_matches(&['\r', '\n'][..]).to_owned(),
+            })
+        } else {
+            auth::find_credentials(&image.registry_host())
+        };
+        download_image(&image, &args.arg_targetdir, &platform, creds.as_ref())
     } else {
         unreachable!("no cmd")
     }
 }
 
-pub fn commonise_tars(tnames: &[&str]) {
-    println!("Opening tars");
-    let ars: Vec<tar::Archive<_>> = tnames.iter()
-                                          .map(|tname| {
-                                              let file = fs::File::open(tname).unwrap();
-                                              Archive::new(file)
-                                          })
-                                          .collect();
-    let mut arfiless: Vec<Vec<tar::Entry<_>>> = vec![];
-    let mut ignoredfiless: Vec<Vec<tar::Entry<_>>> = vec![];
-    for (ar, tname) in ars.iter().zip(tnames) {
-        let (arfiles, ignoredfiles) = get_archive_entries(ar, tname);
-        arfiless.push(arfiles);
-        ignoredfiless.push(ignoredfiles);
-    }
-
-    println!("Phase 1: metadata compare");
-    let mut arheadmaps: Vec<HashMap<HashableHeader, &mut tar::Entry<_>>> =
-        arfiless.iter_mut().map(|arfiles| get_header_map(arfiles)).collect();
-    // ideally would be &HashableHeader, but that borrows the maps as immutable
-    // which then conflicts with the mutable borrow later because a borrow of
-    // either keys or values applies to the whole hashmap
-    // https://github.com/rust-lang/rfcs/issues/1215
-    let commonheaders: Vec<HashableHeader> = find_common_keys(&arheadmaps);
-    let p1commonsize = commonheaders.iter().fold(0, |sum, h| sum + h.0.size().unwrap());
-    let p1commonsizestr = format_num_bytes(p1commonsize);
-    println!("Phase 1 complete: possible {} files with {}",
-             commonheaders.len(),
-             p1commonsizestr);
-
-    println!("Phase 2: data compare");
-    let mut commonfiles: Vec<HashableHeader> = vec![];
-    // TODO: sort by offset in archive? means not seeking backwards
-    for (i, hheader) in commonheaders.iter().enumerate() {
-        let mut files: Vec<&mut tar::Entry<_>> = arheadmaps.iter_mut()
-                                                           .map(|arhm| {
-                                                               &mut **arhm.get_mut(hheader).unwrap()
-                                                           })
-                                                           .collect();
-        // Do the files have the same contents?
-        // Note we've verified they have the same size by now
-        if readers_identical(&mut files) {
-            commonfiles.push(hheader.clone());
-        }
-        if i % 100 == 0 {
-            print!("    Done {}\r", i);
-            io::stdout().flush().unwrap();
-        }
-        // Reset the file - each entry keeps track of its own position
-        for f in &mut files {
-            f.seek(io::SeekFrom::Start(0)).unwrap();
-        }
-    }
-    let p2commonsize = commonfiles.iter().fold(0, |sum, h| sum + h.0.size().unwrap());
-    let p2commonsizestr = format_num_bytes(p2commonsize);
-    println!("Phase 2 complete: actual {} files with {}",
-             commonfiles.len(),
-             p2commonsizestr);
+fn commonise_options(args: &Args) -> CommoniseOptions {
+    CommoniseOptions {
+        contenthash: if args.flag_content_hash { Some(MatchFields::parse(&args.flag_match)) } else { None },
+        tree: args.flag_tree,
+        threads: args.flag_threads,
+        commonname: args.flag_common_name.clone(),
+        nametemplate: args.flag_name.clone(),
+        force: args.flag_force,
+        compression: Compression::parse(&args.flag_compress),
+        outdir: PathBuf::from(&args.flag_outdir),
+    }
+}
+
+fn analyse_save(savepath: &str, asjson: bool) {
+    let src = SaveSource::new(savepath);
+    let analysis = save::analyse_save(&src, !asjson);
+    let tarpaths: Vec<&str> = analysis.images.iter().map(|image| &image.tarpath[..]).collect();
+
+    if asjson {
+        #[derive(RustcEncodable)]
+        struct AnalyseOutput<'a> {
+            analysis: &'a SaveAnalysis,
+            commonise_args: &'a [&'a str],
+        }
+        let output = AnalyseOutput { analysis: &analysis, commonise_args: &tarpaths };
+        println!("{}", json::encode(&output).unwrap());
+        return
+    }
+
+    println!("");
+    if src.is_tar() {
+        println!("Extract the save before commonising:");
+        println!("    mkdir {0} && tar -C {0} -xf {1}", src.extract_dir().display(), savepath);
+        println!("");
+    }
+    println!("Suggested commonisation:");
+    println!("    dayer commonise-tar {}", tarpaths.join(" "));
+
+    println!("");
+    println!("Creating recombination commands:");
+    let scriptfile = write_recombine_script(&analysis);
+    println!("(you can just run {} to recombine)", scriptfile);
+}
+
+fn write_recombine_script(analysis: &SaveAnalysis) -> String {
+    let mut randbytes = [0u8; 5];
+    File::open("/dev/urandom").unwrap().read_exact(&mut randbytes).unwrap();
+    let randhex: String = randbytes.iter().map(|b| format!("{:02x}", b)).collect();
+    let randid = &randhex[..9];
+    let scriptfile = format!("script_{}.sh", randid);
+    let dfile = format!("Dockerfile_{}", randid);
+
+    let mut cmds = vec![];
+    if let Some(ref parent) = analysis.parent {
+        cmds.push(format!("docker tag {} parenttmp_{}", parent, randid));
+        cmds.push(format!("echo -e 'FROM parenttmp_{}\\nADD common.tar /' > {}", randid, dfile));
+    } else {
+        cmds.push(format!("echo -e 'FROM scratch\\nADD common.tar /' > {}", dfile));
+    }
+    cmds.push(format!("tar c {0} common.tar | docker build -f {0} --tag commontmp_{1} -", dfile, randid));
+    for (i, image) in analysis.images.iter().enumerate() {
+        cmds.push(format!("echo -e 'FROM commontmp_{}\\nADD individual_{}.tar /' > {}", randid, i, dfile));
+        cmds.push(format!("tar c {0} individual_{1}.tar | docker build -f {0} --tag {2} -",
+                          dfile, i, image.repotag()));
+    }
+    if analysis.parent.is_some() {
+        cmds.push(format!("docker rmi commontmp_{0} parenttmp_{0} # just untagging", randid));
+    }
+    cmds.push(format!("rm {}", dfile));
+
+    println!("```");
+    for cmd in &cmds {
+        println!("{}", cmd);
+    }
+    println!("```");
+
+    let mut script = BufWriter::new(File::create(&scriptfile).unwrap());
+    for line in &["#!/bin/bash", "set -o errexit", "set -o pipefail", "set -o nounset", "set -o xtrace"] {
+        writeln!(script, "{}", line).unwrap();
+    }
+    for cmd in &cmds {
+        writeln!(script, "{}", cmd).unwrap();
+    }
+    writeln!(script, "rm {}", scriptfile).unwrap();
+    drop(script);
+    fs::set_permissions(&scriptfile, fs::Permissions::from_mode(0o755)).unwrap();
+    scriptfile
+}
+
+// Returns the common files, along with how many files (and bytes) looked
+// common from their metadata alone
+fn commonise_by_header(arheadmaps: &[HashMap<HashableHeader, EntryInfo>],
+                       arlinkgroups: &[HashMap<PathBuf, Vec<&HashableHeader>>],
+                       verbose: bool)
+                       -> (Vec<HashableHeader>, usize, u64) {
+    if verbose {
+        println!("Phase 1: metadata compare")
+    }
+    let commonheaders: Vec<HashableHeader> = find_common_keys(arheadmaps);
+    let commonheaders = filter_link_groups(commonheaders, arlinkgroups, |_, h| h.clone());
+    let p1commonfiles = commonheaders.len();
+    let p1commonsize = commonheaders.iter().fold(0, |sum, h| sum + h.size());
+    if verbose {
+        println!("Phase 1 complete: possible {} files with {}",
+                 p1commonfiles,
+                 format_num_bytes(p1commonsize));
+        println!("Phase 2: data compare")
+    }
+    // Contents were hashed when loading, so there's no need to go back to the
+    // tars. Note we've verified they have the same size by now.
+    let commonfiles: Vec<HashableHeader> =
+        commonheaders.into_iter()
+                     .filter(|h| {
+                         let digest = &arheadmaps[0][h].digest;
+                         arheadmaps.iter().all(|arhm| &arhm[h].digest == digest)
+                     })
+                     .collect();
+    // Content may have differed for a file that has hard links to it
+    let commonfiles = filter_link_groups(commonfiles, arlinkgroups, |_, h| h.clone());
+    if verbose {
+        let p2commonsize = commonfiles.iter().fold(0, |sum, h| sum + h.size());
+        println!("Phase 2 complete: actual {} files with {}",
+                 commonfiles.len(),
+                 format_num_bytes(p2commonsize))
+    }
+    (commonfiles, p1commonfiles, p1commonsize)
+}
+
+fn commonise_by_content(arheadmaps: &[HashMap<HashableHeader, EntryInfo>],
+                        arlinkgroups: &[HashMap<PathBuf, Vec<&HashableHeader>>],
+                        fields: &MatchFields,
+                        verbose: bool)
+                        -> Vec<HashableHeader> {
+    if verbose {
+        println!("Phase 1: content compare")
+    }
+    let tokey = |i: usize, h: &HashableHeader| ContentKey::new(h, arheadmaps[i][h].digest.clone(), fields);
+    let arkeymaps: Vec<HashMap<ContentKey, &HashableHeader>> =
+        arheadmaps.iter().enumerate().map(|(i, arhm)| arhm.keys().map(|h| (tokey(i, h), h)).collect()).collect();
+    let commonkeys: Vec<ContentKey> = find_common_keys(&arkeymaps);
+    let commonfiles: Vec<HashableHeader> = commonkeys.iter().map(|k| arkeymaps[0][k].clone()).collect();
+    let commonfiles = filter_link_groups(commonfiles, arlinkgroups, &tokey);
+    if verbose {
+        let commonsize = commonfiles.iter().fold(0, |sum, h| sum + h.size());
+        println!("Phase 1 complete: actual {} files with {}",
+                 commonfiles.len(),
+                 format_num_bytes(commonsize))
+    }
+    commonfiles
+}
+
+// Create a holding-place directory for a shared layer as it will be
+// overwritten by the layer above. Only used for directories every tar the layer
+// is for has, so the layers above always do overwrite it - a directory a tar
+// doesn't have is left alone, as it may be in the image beneath.
+fn minimalmkdir(dirpath: &Path) -> HashableHeader {
+    let mut newdir = HashableHeader::new(&tar::Header::new());
+    newdir.0.set_mode(0o755);
+    newdir.0.set_uid(0);
+    newdir.0.set_gid(0);
+    newdir.0.set_mtime(0);
+    newdir.0.link[0] = b'5'; // dir
+    // linkname: irrelevant
+    // cksum: calculated when setting the path
+    newdir.set_path(dirpath);
+    newdir
+}
+
+// Metadata that wasn't compared may differ between the tars, so don't let the
+// first tar's values leak into a shared layer
+fn normalise_shared(hheader: &HashableHeader, contenthash: Option<MatchFields>) -> HashableHeader {
+    let mut hheader = hheader.clone();
+    if let Some(fields) = contenthash {
+        hheader.clear_times();
+        if !fields.mode {
+            hheader.clear_mode()
+        }
+        if !fields.owner {
+            hheader.clear_owner()
+        }
+        if !fields.xattrs {
+            hheader.clear_xattrs()
+        }
+    }
+    hheader
+}
+
+// Directories each tar has entries for
+fn ar_dirs(arheadmaps: &[HashMap<HashableHeader, EntryInfo>]) -> Vec<HashSet<PathBuf>> {
+    arheadmaps.iter().map(|arhm| arhm.keys().filter(|h| h.is_dir()).map(|h| normpath(&h.path())).collect()).collect()
+}
+
+// Leave files out of the common layer where the placeholder directories they'd
+// need there cost more than sharing them saves. Each directory is worth the
+// saving from common entries directly inside it, plus that of any subdirectory
+// worth keeping, less the cost of a placeholder (or plus the saving from
+// sharing it, if it's common itself). Directories some tar doesn't have get no
+// placeholder, so cost nothing. Anything below a directory not worth keeping is
+// left out. Returns the common files left and the paths taken out.
+fn prune_common_dirs(commonfiles: Vec<HashableHeader>,
+                     arheadmaps: &[HashMap<HashableHeader, EntryInfo>],
+                     linkgroups: &HashMap<PathBuf, Vec<&HashableHeader>>)
+                     -> (Vec<HashableHeader>, HashSet<PathBuf>) {
+    let numsaved = arheadmaps.len() as i64 - 1;
+    let ardirs = ar_dirs(arheadmaps);
+    let mut dirvalues: HashMap<PathBuf, i64> = HashMap::new();
+    for hheader in &commonfiles {
+        let path = normpath(&hheader.path());
+        let parents = parent_dirs(&path);
+        if !hheader.is_dir() {
+            if let Some(parent) = parents.first() {
+                *dirvalues.entry(parent.clone()).or_insert(0) += numsaved * entry_cost(hheader) as i64
+            }
+        }
+        for dir in parents {
+            dirvalues.entry(dir).or_insert(0);
+        }
+    }
+    let commondirs: HashSet<PathBuf> = commonfiles.iter().filter(|h| h.is_dir()).map(|h| normpath(&h.path())).collect();
+    for dir in &commondirs {
+        dirvalues.entry(dir.clone()).or_insert(0);
+    }
+    let mut dirs: Vec<PathBuf> = dirvalues.keys().cloned().collect();
+    // Deepest first, so subdirectories are totalled before their parents
+    dirs.sort_by(|d1, d2| d2.components().count().cmp(&d1.components().count()));
+    for dir in dirs {
+        let value = {
+            let value = dirvalues.get_mut(&dir).unwrap();
+            *value += if commondirs.contains(&dir) {
+                numsaved * 512
+            } else if ardirs.iter().all(|dirs| dirs.contains(&dir)) {
+                -512
+            } else {
+                0
+            };
+            *value
+        };
+        if let Some(parent) = parent_dirs(&dir).first() {
+            if value > 0 {
+                *dirvalues.get_mut(parent).unwrap() += value
+            }
+        }
+    }
+
+    let mut pruned: HashSet<PathBuf> = HashSet::new();
+    for hheader in &commonfiles {
+        let path = normpath(&hheader.path());
+        let mut dirs = parent_dirs(&path);
+        if hheader.is_dir() {
+            dirs.push(path.clone())
+        }
+        if dirs.iter().any(|dir| dirvalues[dir] <= 0) {
+            pruned.insert(path);
+        }
+    }
+    remove_pruned(commonfiles, pruned, linkgroups)
+}
+
+// An opaque whiteout hides everything beneath its directory in lower layers,
+// including the common layer, so nothing beneath one in any tar can be shared
+// unless the whiteout is shared too. Returns the common files left and the
+// paths taken out.
+fn prune_opaque_dirs(commonfiles: Vec<HashableHeader>,
+                     arheadmaps: &[HashMap<HashableHeader, EntryInfo>],
+                     linkgroups: &HashMap<PathBuf, Vec<&HashableHeader>>)
+                     -> (Vec<HashableHeader>, HashSet<PathBuf>) {
+    let opaquedirs: HashSet<PathBuf> = arheadmaps.iter()
+                                                 .flat_map(|arhm| arhm.keys().filter_map(|h| h.opaque_dir()))
+                                                 .collect();
+    let commonopaquedirs: HashSet<PathBuf> = commonfiles.iter().filter_map(|h| h.opaque_dir()).collect();
+    let pruned: HashSet<PathBuf> =
+        commonfiles.iter()
+                   .map(|h| normpath(&h.path()))
+                   .filter(|path| {
+                       parent_dirs(path).iter().any(|dir| opaquedirs.contains(dir) && !commonopaquedirs.contains(dir))
+                   })
+                   .collect();
+    remove_pruned(commonfiles, pruned, linkgroups)
+}
+
+// Take paths out of the common files, along with the rest of their hard link
+// groups
+fn remove_pruned(commonfiles: Vec<HashableHeader>,
+                 mut pruned: HashSet<PathBuf>,
+                 linkgroups: &HashMap<PathBuf, Vec<&HashableHeader>>)
+                 -> (Vec<HashableHeader>, HashSet<PathBuf>) {
+    for group in linkgroups.values() {
+        if group.iter().any(|h| pruned.contains(&normpath(&h.path()))) {
+            pruned.extend(group.iter().map(|h| normpath(&h.path())))
+        }
+    }
+    let commonfiles = commonfiles.into_iter().filter(|h| !pruned.contains(&normpath(&h.path()))).collect();
+    (commonfiles, pruned)
+}
+
+pub fn commonise_tars(tnames: &[&str], opts: &CommoniseOptions) -> Vec<OutputLayer> {
+    let commonpath = opts.common_path();
+    let individualpaths: Vec<PathBuf> =
+        tnames.iter().enumerate().map(|(i, tname)| opts.individual_path(i, tname)).collect();
+    let graphpath = opts.graph_path();
+    {
+        let mut outpaths: Vec<&Path> = individualpaths.iter().map(|p| p.as_path()).collect();
+        outpaths.push(&graphpath);
+        if !opts.tree {
+            outpaths.push(&commonpath)
+        }
+        check_outputs(&outpaths, &to_paths(tnames), opts.force);
+    }
+    fs::create_dir_all(&opts.outdir).unwrap();
+
+    let arheadmaps: Vec<HashMap<HashableHeader, EntryInfo>> = parallel_map(opts.threads, tnames.to_vec(), |tname| {
+        index_archive(tname, true)
+    });
+
+    let arlinkgroups: Vec<HashMap<PathBuf, Vec<&HashableHeader>>> =
+        arheadmaps.iter().map(|arhm| get_link_groups(arhm.keys())).collect();
+    if opts.tree {
+        return commonise_tree(tnames, &individualpaths, &graphpath, &arheadmaps, &arlinkgroups, opts)
+    }
+    let commonfiles = match opts.contenthash {
+        Some(ref fields) => commonise_by_content(&arheadmaps, &arlinkgroups, fields, true),
+        None => commonise_by_header(&arheadmaps, &arlinkgroups, true).0,
+    };
+    let (commonfiles, hidden) = prune_opaque_dirs(commonfiles, &arheadmaps, &arlinkgroups[0]);
+    if !hidden.is_empty() {
+        println!("Leaving {} files out of the common layer as opaque whiteouts above would hide them",
+                 hidden.len())
+    }
+    let (commonfiles, pruned) = prune_common_dirs(commonfiles, &arheadmaps, &arlinkgroups[0]);
+    if !pruned.is_empty() {
+        println!("Leaving {} files out of the common layer as their directories cost more than they save",
+                 pruned.len())
+    }
+
+    println!("Phase 3a: preparing for layer creation");
+    let tonormpath = |h: &HashableHeader| normpath(&h.path());
+    let commonmap: HashMap<PathBuf, &HashableHeader> = commonfiles.iter()
+                                                                  .map(|h| (tonormpath(h), h))
+                                                                  .collect();
+    // Directories the common layer will have, shared or as placeholders
+    let commondirs: HashSet<PathBuf> = commonfiles.iter()
+                                                  .flat_map(|h| parent_dirs(&tonormpath(h)))
+                                                  .chain(commonfiles.iter().filter(|h| h.is_dir()).map(&tonormpath))
+                                                  .collect();
+    println!("Phase 3a complete");
+
+    println!("Phase 3b: layer creation");
+    let mut jobs = vec![LayerJob {
+        outpath: commonpath.clone(),
+        // It doesn't matter which tar, these are common files!
+        src: 0,
+        headers: commonfiles.iter().collect(),
+        dirs: HashMap::new(),
+        skipdirs: HashSet::new(),
+        inputs: (0..tnames.len()).collect(),
+        parent: None,
+    }];
+    for (i, arheadmap) in arheadmaps.iter().enumerate() {
+        // Restore directories with this tar's own header, which may differ
+        // from the common one with --content-hash
+        let dirs: HashMap<PathBuf, &HashableHeader> =
+            arheadmap.keys().filter(|h| h.is_dir()).map(|h| (tonormpath(h), h)).collect();
+        // ...unless the common layer already has them right. One this tar
+        // doesn't have isn't in the common layer either, so is left alone.
+        let skipdirs: HashSet<PathBuf> =
+            commondirs.iter()
+                      .filter(|dir| match (dirs.get(*dir), commonmap.get(*dir)) {
+                          (None, _) => true,
+                          (Some(own), Some(common)) => normalise_shared(common, opts.contenthash) == **own,
+                          (Some(_), None) => false,
+                      })
+                      .cloned()
+                      .collect();
+        jobs.push(LayerJob {
+            outpath: individualpaths[i].clone(),
+            src: i,
+            headers: arheadmap.keys().filter(|h| !commonmap.contains_key(&tonormpath(h))).collect(),
+            dirs: dirs,
+            skipdirs: skipdirs,
+            inputs: vec![i],
+            parent: Some(0),
+        })
+    }
+    let digests = write_layers(tnames, &jobs, &arheadmaps, opts);
+    let layers = describe_layers(tnames, &jobs, digests, &graphpath);
+    println!("Phase 3b complete: created {} and {} individual tars",
+             commonpath.display(),
+             arheadmaps.len());
+    layers
+}
+
+#[derive(RustcEncodable)]
+pub struct CommoniseReport {
+    pub inputs: Vec<String>,
+    // Files that looked common from their metadata, before comparing contents
+    pub candidate_files: usize,
+    pub candidate_bytes: u64,
+    pub common_files: usize,
+    pub common_bytes: u64,
+    // Common files are stored once rather than once per tar
+    pub saved_bytes: u64,
+    pub top_dirs: Vec<DirSavings>,
+    pub near_misses: Vec<NearMiss>,
+    // Near misses with the same reasons, most bytes first
+    pub near_miss_groups: Vec<NearMissGroup>,
+}
+
+// Common files directly inside a directory
+#[derive(RustcEncodable)]
+pub struct DirSavings {
+    pub dir: String,
+    pub files: usize,
+    pub bytes: u64,
+}
+
+// A path in every tar which wasn't commonised, with its size in each tar and
+// why it wasn't commonised - the header fields that differed, "contents" if the
+// data differed or "hard links" if only other files linked to it differed
+#[derive(RustcEncodable)]
+pub struct NearMiss {
+    pub path: String,
+    pub sizes: Vec<u64>,
+    pub reasons: Vec<String>,
+}
+
+#[derive(RustcEncodable)]
+pub struct NearMissGroup {
+    pub reasons: Vec<String>,
+    pub files: usize,
+    pub bytes: u64,
+    // Largest first
+    pub paths: Vec<String>,
+}
+
+// How many directories to report, and near misses to print as text
+const REPORT_LEN: usize = 10;
+
+// Work out what commonise_tars would share, without writing anything
+pub fn report_commonise(tnames: &[&str], opts: &CommoniseOptions, verbose: bool) -> CommoniseReport {
+    assert!(!opts.tree, "a report can't be made for a layer tree");
+    let arheadmaps: Vec<HashMap<HashableHeader, EntryInfo>> = parallel_map(opts.threads, tnames.to_vec(), |tname| {
+        index_archive(tname, verbose)
+    });
+    let arlinkgroups: Vec<HashMap<PathBuf, Vec<&HashableHeader>>> =
+        arheadmaps.iter().map(|arhm| get_link_groups(arhm.keys())).collect();
+    let (commonfiles, candidatefiles, candidatebytes) = match opts.contenthash {
+        Some(ref fields) => {
+            let commonfiles = commonise_by_content(&arheadmaps, &arlinkgroups, fields, verbose);
+            let (numfiles, numbytes) = (commonfiles.len(), commonfiles.iter().fold(0, |sum, h| sum + h.size()));
+            (commonfiles, numfiles, numbytes)
+        },
+        None => commonise_by_header(&arheadmaps, &arlinkgroups, verbose),
+    };
+    let (commonfiles, hidden) = prune_opaque_dirs(commonfiles, &arheadmaps, &arlinkgroups[0]);
+    let (commonfiles, pruned) = prune_common_dirs(commonfiles, &arheadmaps, &arlinkgroups[0]);
+    let commonbytes = commonfiles.iter().fold(0, |sum, h| sum + h.size());
+
+    let mut dirsavings: HashMap<PathBuf, (usize, u64)> = HashMap::new();
+    for hheader in commonfiles.iter().filter(|h| !h.is_dir()) {
+        let dir = normpath(&hheader.path()).parent().map_or_else(PathBuf::new, |p| p.to_path_buf());
+        let saving = dirsavings.entry(dir).or_insert((0, 0));
+        saving.0 += 1;
+        saving.1 += hheader.size();
+    }
+    let mut topdirs: Vec<DirSavings> = dirsavings.into_iter().map(|(dir, (files, bytes))| DirSavings {
+        dir: if dir == Path::new("") { ".".to_owned() } else { dir.to_str().unwrap().to_owned() },
+        files: files,
+        bytes: bytes,
+    }).collect();
+    topdirs.sort_by(|d1, d2| (d2.bytes, &d1.dir).cmp(&(d1.bytes, &d2.dir)));
+    topdirs.truncate(REPORT_LEN);
+
+    let commonpaths: HashSet<PathBuf> = commonfiles.iter().map(|h| normpath(&h.path())).collect();
+    let prunedfor = |path: &Path| if hidden.contains(path) {
+        Some("opaque whiteout")
+    } else if pruned.contains(path) {
+        Some("directories")
+    } else {
+        None
+    };
+    let arpathmaps: Vec<HashMap<PathBuf, &HashableHeader>> =
+        arheadmaps.iter().map(|arhm| arhm.keys().map(|h| (normpath(&h.path()), h)).collect()).collect();
+    let mut nearmisses: Vec<NearMiss> =
+        arpathmaps[0].keys()
+                     .filter(|p| !commonpaths.contains(*p) && arpathmaps.iter().all(|pm| pm.contains_key(*p)))
+                     .map(|p| {
+                         let headers: Vec<&HashableHeader> = arpathmaps.iter().map(|pm| pm[p]).collect();
+                         NearMiss {
+                             path: p.to_str().unwrap().to_owned(),
+                             sizes: headers.iter().map(|h| h.size()).collect(),
+                             reasons: near_miss_reasons(&arheadmaps, &headers, opts.contenthash, prunedfor(p.as_path())),
+                         }
+                     })
+                     .collect();
+    let maxsize = |nm: &NearMiss| *nm.sizes.iter().max().unwrap();
+    nearmisses.sort_by(|nm1, nm2| (maxsize(nm2), &nm1.path).cmp(&(maxsize(nm1), &nm2.path)));
+    let mut nearmissgroups: Vec<NearMissGroup> = {
+        let mut groups: BTreeMap<&[String], NearMissGroup> = BTreeMap::new();
+        for nearmiss in &nearmisses {
+            let group = groups.entry(&nearmiss.reasons[..]).or_insert_with(|| NearMissGroup {
+                reasons: nearmiss.reasons.clone(),
+                files: 0,
+                bytes: 0,
+                paths: vec![],
+            });
+            group.files += 1;
+            group.bytes += maxsize(nearmiss);
+            group.paths.push(nearmiss.path.clone());
+        }
+        groups.into_iter().map(|(_, group)| group).collect()
+    };
+    nearmissgroups.sort_by(|g1, g2| (g2.bytes, g2.files).cmp(&(g1.bytes, g1.files)));
+
+    CommoniseReport {
+        inputs: tnames.iter().map(|t| (*t).to_owned()).collect(),
+        candidate_files: candidatefiles,
+        candidate_bytes: candidatebytes,
+        common_files: commonfiles.len(),
+        common_bytes: commonbytes,
+        saved_bytes: commonbytes * (tnames.len() as u64 - 1),
+        top_dirs: topdirs,
+        near_misses: nearmisses,
+        near_miss_groups: nearmissgroups,
+    }
+}
+
+// Why the headers for a path, one from each tar, weren't commonised. With
+// --content-hash only differences in the fields being matched count.
+fn near_miss_reasons(arheadmaps: &[HashMap<HashableHeader, EntryInfo>],
+                     headers: &[&HashableHeader],
+                     contenthash: Option<MatchFields>,
+                     pruned: Option<&str>)
+                     -> Vec<String> {
+    let mut reasons: Vec<&str> = vec![];
+    for (i, hheader) in headers.iter().enumerate().skip(1) {
+        for field in headers[0].differences(hheader) {
+            let matched = match contenthash {
+                None => true,
+                Some(fields) => match field {
+                    "mode" => fields.mode,
+                    "uid" | "gid" => fields.owner,
+                    "xattrs" => fields.xattrs,
+                    "type" | "linkname" | "device" | "sparse" => true,
+                    _ => false,
+                },
+            };
+            if matched && !reasons.contains(&field) {
+                reasons.push(field)
+            }
+        }
+        if arheadmaps[i][*hheader].digest != arheadmaps[0][headers[0]].digest && !reasons.contains(&"contents") {
+            reasons.push("contents")
+        }
+    }
+    if reasons.is_empty() {
+        // Everything matched, so it was taken out of the common layer
+        // afterwards or split from a hard link group
+        reasons.push(pruned.unwrap_or("hard links"))
+    }
+    reasons.into_iter().map(|r| r.to_owned()).collect()
+}
+
+fn print_report(report: &CommoniseReport) {
+    println!("");
+    println!("Dry run, no tars written");
+    println!("Metadata matches: {} files with {}",
+             report.candidate_files,
+             format_num_bytes(report.candidate_bytes));
+    println!("Common: {} files with {}", report.common_files, format_num_bytes(report.common_bytes));
+    println!("Estimated saving: {} across {} tars",
+             format_num_bytes(report.saved_bytes),
+             report.inputs.len());
+    println!("Top directories by shared bytes:");
+    for dirsaving in &report.top_dirs {
+        println!("    {}: {} in {} files", dirsaving.dir, format_num_bytes(dirsaving.bytes), dirsaving.files);
+    }
+    println!("Near misses (in every tar but not commonised): {}", report.near_misses.len());
+    for nearmiss in report.near_misses.iter().take(REPORT_LEN) {
+        let sizes: Vec<String> = nearmiss.sizes.iter().map(|&s| format_num_bytes(s)).collect();
+        println!("    {}: {} ({})", nearmiss.path, sizes.join(", "), nearmiss.reasons.join(", "));
+    }
+    if report.near_misses.len() > REPORT_LEN {
+        println!("    ... use --json to list all of them");
+    }
+    println!("Near misses by what differed:");
+    for group in &report.near_miss_groups {
+        println!("    {}: {} files with {}, e.g. {}",
+                 group.reasons.join(", "),
+                 group.files,
+                 format_num_bytes(group.bytes),
+                 group.paths[..cmp::min(group.paths.len(), 3)].join(" "));
+    }
+}
+
+// An output tar to create from the headers of one input
+struct LayerJob<'a> {
+    outpath: PathBuf,
+    src: usize,
+    headers: Vec<&'a HashableHeader>,
+    // Headers to restore parent directories with, anything else gets a
+    // placeholder if all the inputs have it
+    dirs: HashMap<PathBuf, &'a HashableHeader>,
+    // Parent directories the layer beneath already has as they should be
+    skipdirs: HashSet<PathBuf>,
+    // Which tars the layer is for, if there's more than one it's shared and
+    // needs normalising
+    inputs: Vec<usize>,
+    // The job for the layer beneath
+    parent: Option<usize>,
+}
+
+// Write out layers in parallel, each opening its input separately so they can
+// all read sequentially
+fn write_layers(tnames: &[&str],
+                jobs: &[LayerJob],
+                arheadmaps: &[HashMap<HashableHeader, EntryInfo>],
+                opts: &CommoniseOptions)
+                -> Vec<LayerDigests> {
+    let ardirs = ar_dirs(arheadmaps);
+    parallel_map(opts.threads, jobs.iter().collect(), |job: &LayerJob| {
+        let mut archive = LayerReader::open(Path::new(tnames[job.src]));
+        let mkdir = |dirpath: &Path| if job.skipdirs.contains(dirpath) {
+            None
+        } else {
+            match job.dirs.get(dirpath) {
+                Some(hheader) => Some((*hheader).clone()),
+                // Every input has it, and restores its own header above
+                None if job.inputs.iter().all(|&i| ardirs[i].contains(dirpath)) => Some(minimalmkdir(dirpath)),
+                // Some input didn't have the dir, so whatever the image beneath
+                // has for it must be kept - extracting creates it if needed
+                None => None,
+            }
+        };
+        let normalise = |hheader: &HashableHeader| {
+            if job.inputs.len() > 1 { normalise_shared(hheader, opts.contenthash) } else { hheader.clone() }
+        };
+        let digests = make_layer_tar(&job.outpath,
+                                     opts.compression,
+                                     job.headers.iter().cloned(),
+                                     &mut archive,
+                                     &arheadmaps[job.src],
+                                     &mkdir,
+                                     &normalise);
+        println!("    created {} ({})", job.outpath.display(), digests.digest);
+        digests
+    })
+}
+
+// How many bytes an entry takes up in a tar
+fn entry_cost(hheader: &HashableHeader) -> u64 {
+    512 + (hheader.size() + 511) / 512 * 512
+}
+
+#[derive(RustcEncodable, RustcDecodable)]
+pub struct OutputLayer {
+    pub name: String,
+    pub parent: Option<String>,
+    pub inputs: Vec<String>,
+    pub files: usize,
+    pub bytes: u64,
+    pub diff_id: String,
+    pub digest: String,
+    pub size: u64,
+}
+
+// Describe the layers that were written, in the graph file as well
+fn describe_layers(tnames: &[&str], jobs: &[LayerJob], digests: Vec<LayerDigests>, graphpath: &Path)
+                   -> Vec<OutputLayer> {
+    let name = |job: &LayerJob| job.outpath.file_name().unwrap().to_str().unwrap().to_owned();
+    let layers: Vec<OutputLayer> = jobs.iter().zip(digests).map(|(job, digests)| OutputLayer {
+        name: name(job),
+        parent: job.parent.map(|p| name(&jobs[p])),
+        inputs: job.inputs.iter().map(|&i| tnames[i].to_owned()).collect(),
+        files: job.headers.len(),
+        bytes: job.headers.iter().fold(0, |sum, h| sum + entry_cost(h)),
+        diff_id: digests.diff_id,
+        digest: digests.digest,
+        size: digests.size,
+    }).collect();
+    let mut graphfile = File::create(graphpath).unwrap();
+    graphfile.write_all(json::encode(&layers).unwrap().as_bytes()).unwrap();
+    layers
+}
+
+fn commonise_tree(tnames: &[&str],
+                  individualpaths: &[PathBuf],
+                  graphpath: &Path,
+                  arheadmaps: &[HashMap<HashableHeader, EntryInfo>],
+                  arlinkgroups: &[HashMap<PathBuf, Vec<&HashableHeader>>],
+                  opts: &CommoniseOptions)
+                  -> Vec<OutputLayer> {
+    match opts.contenthash {
+        Some(ref fields) => {
+            let tokey = |i: usize, h: &HashableHeader| ContentKey::new(h, arheadmaps[i][h].digest.clone(), fields);
+            make_layer_tree(tnames, individualpaths, graphpath, arheadmaps, arlinkgroups, opts, tokey)
+        },
+        None => {
+            let tokey = |i: usize, h: &HashableHeader| (h.clone(), arheadmaps[i][h].digest.clone());
+            make_layer_tree(tnames, individualpaths, graphpath, arheadmaps, arlinkgroups, opts, tokey)
+        },
+    }
+}
+
+fn make_layer_tree<K, F>(tnames: &[&str],
+                         individualpaths: &[PathBuf],
+                         graphpath: &Path,
+                         arheadmaps: &[HashMap<HashableHeader, EntryInfo>],
+                         arlinkgroups: &[HashMap<PathBuf, Vec<&HashableHeader>>],
+                         opts: &CommoniseOptions,
+                         tokey: F)
+                         -> Vec<OutputLayer>
+    where K: Clone + Eq + Hash, F: Fn(usize, &HashableHeader) -> K
+{
+    println!("Phase 1: finding shared files");
+    // Hard links and the file they point to must end up in the same layer, so
+    // members of a link group are keyed by the whole group too. Anything
+    // beneath an opaque whiteout is keyed by the whiteout as well, so it's
+    // never in a layer below the whiteout, which would hide it.
+    let arkeymaps: Vec<HashMap<(K, Option<Vec<K>>, Vec<K>), &HashableHeader>> =
+        arheadmaps.iter().zip(arlinkgroups).enumerate().map(|(i, (arhm, linkgroups))| {
+            let mut grouped: HashMap<PathBuf, Vec<K>> = HashMap::new();
+            for group in linkgroups.values() {
+                let groupkeys: Vec<K> = group.iter().map(|h| tokey(i, h)).collect();
+                for hheader in group {
+                    grouped.insert(normpath(&hheader.path()), groupkeys.clone());
+                }
+            }
+            let opaques: HashMap<PathBuf, K> =
+                arhm.keys().filter_map(|h| h.opaque_dir().map(|dir| (dir, tokey(i, h)))).collect();
+            arhm.keys().map(|h| {
+                let path = normpath(&h.path());
+                let hiddenby: Vec<K> = parent_dirs(&path).iter().filter_map(|dir| opaques.get(dir).cloned()).collect();
+                ((tokey(i, h), grouped.get(&path).cloned(), hiddenby), h)
+            }).collect()
+        }).collect();
+    let mut keyinputs: HashMap<&(K, Option<Vec<K>>, Vec<K>), Vec<usize>> = HashMap::new();
+    for (i, arkeymap) in arkeymaps.iter().enumerate() {
+        for key in arkeymap.keys() {
+            keyinputs.entry(key).or_insert_with(Vec::new).push(i)
+        }
+    }
+    let mut setcosts: BTreeMap<Vec<usize>, u64> = BTreeMap::new();
+    for (key, inputs) in &keyinputs {
+        if inputs.len() > 1 {
+            *setcosts.entry(inputs.clone()).or_insert(0) += entry_cost(arkeymaps[inputs[0]][*key])
+        }
+    }
+    println!("Phase 1 complete: {} distinct sets of tars share files", setcosts.len());
+
+    println!("Phase 2: building layer tree");
+    let nodes = tree::build_layer_tree(tnames.len(), &setcosts);
+    // Each node takes its files from the first of its inputs
+    let mut nodefiles: Vec<Vec<HashableHeader>> = nodes.iter().map(|_| vec![]).collect();
+    for (key, inputs) in &keyinputs {
+        for n in tree::place(&nodes, inputs) {
+            nodefiles[n].push(arkeymaps[nodes[n].inputs[0]][*key].clone())
+        }
+    }
+    let numshared = nodes.len() - tnames.len();
+    let nodepaths: Vec<PathBuf> = nodes.iter().enumerate().map(|(n, node)| {
+        if n < tnames.len() {
+            individualpaths[n].clone()
+        } else if node.inputs.len() == tnames.len() {
+            opts.common_path()
+        } else {
+            opts.output_path(format!("shared_{}.tar", n - tnames.len()))
+        }
+    }).collect();
+    let sharedpaths: Vec<&Path> = nodepaths[tnames.len()..].iter().map(|p| p.as_path()).collect();
+    check_outputs(&sharedpaths, &to_paths(tnames), opts.force);
+    println!("Phase 2 complete: {} shared layers", numshared);
+
+    println!("Phase 3: layer creation");
+    let jobs: Vec<LayerJob> = nodes.iter().enumerate().map(|(n, node)| {
+        let src = node.inputs[0];
+        LayerJob {
+            outpath: nodepaths[n].clone(),
+            src: src,
+            headers: nodefiles[n].iter().collect(),
+            // Directories not from this layer's own files are restored with the
+            // header shared by all of its inputs, if there is one
+            dirs: arkeymaps[src].iter()
+                                .filter(|&(key, h)| h.is_dir() && tree::is_subset(&node.inputs, &keyinputs[key]))
+                                .map(|(_, h)| (normpath(&h.path()), *h))
+                                .collect(),
+            skipdirs: HashSet::new(),
+            inputs: node.inputs.clone(),
+            parent: node.parent,
+        }
+    }).collect();
+    let digests = write_layers(tnames, &jobs, arheadmaps, opts);
+    let layers = describe_layers(tnames, &jobs, digests, graphpath);
+    for layer in &layers {
+        println!("    {} has {} files, {}, for {}",
+                 layer.name,
+                 layer.files,
+                 format_num_bytes(layer.bytes),
+                 layer.inputs.join(" "));
+    }
+    println!("Phase 3 complete: layer graph written to {}", graphpath.display());
+
+    let inbytes = arheadmaps.iter().flat_map(|arhm| arhm.keys()).fold(0, |sum, h| sum + entry_cost(h));
+    let outbytes = layers.iter().fold(0, |sum, layer| sum + layer.bytes);
+    println!("Stored {} rather than {}", format_num_bytes(outbytes), format_num_bytes(inbytes));
+    for (i, tname) in tnames.iter().enumerate() {
+        let stack: Vec<&str> = tree::stack(&nodes, i).into_iter().map(|n| &layers[n].name[..]).collect();
+        println!("    {}: {}", tname, stack.join(" -> "));
+    }
+    layers
+}
+
+// Check that extracting the layers listed in the graph file for each input tar
+// gives the same result as extracting the input itself onto the same image,
+// printing and returning any differences - so the layers can't have anything
+// the input doesn't. With --content-hash, metadata that wasn't matched on is
+// ignored.
+pub fn verify_commonise(tnames: &[&str], opts: &CommoniseOptions) -> Vec<String> {
+    let graphpath = opts.graph_path();
+    let mut graphjson = String::new();
+    File::open(&graphpath).unwrap_or_else(|e| panic!("Could not open {}: {}", graphpath.display(), e))
+                          .read_to_string(&mut graphjson).unwrap();
+    let layers: Vec<OutputLayer> = json::decode(&graphjson).unwrap();
+    let stacks: Vec<Vec<PathBuf>> = tnames.iter().map(|tname| {
+        let top = layers.iter()
+                        .find(|layer| layer.inputs == [tname.to_string()])
+                        .unwrap_or_else(|| panic!("no layer for {} in {}", tname, graphpath.display()));
+        let mut stack = vec![opts.outdir.join(&top.name)];
+        let mut parent = top.parent.as_ref();
+        while let Some(name) = parent {
+            stack.push(opts.outdir.join(name));
+            parent = layers.iter().find(|layer| &layer.name == name).unwrap().parent.as_ref();
+        }
+        stack.reverse();
+        stack
+    }).collect();
+
+    let results: Vec<Vec<String>> = parallel_map(opts.threads, tnames.iter().zip(&stacks).collect(), |(tname, stack)| {
+        // Whiteouts are kept, as they still have an effect on whatever the
+        // layers go on top of
+        let expected = overlay_layers(&[*tname][..], true).entries;
+        let actual = overlay_layers(&stack[..], true).entries;
+        let mut problems = vec![];
+        for (path, &(ref hheader, ref source)) in &expected {
+            let &(ref outheader, ref outsource) = match actual.get(path) {
+                Some(entry) => entry,
+                None => {
+                    problems.push(format!("{}: missing", path.display()));
+                    continue
+                },
+            };
+            let (hheader, outheader) = (normalise_shared(hheader, opts.contenthash),
+                                        normalise_shared(outheader, opts.contenthash));
+            if hheader != outheader {
+                problems.push(format!("{}: {} differ", path.display(), hheader.differences(&outheader).join(", ")))
+            }
+            if source.digest != outsource.digest {
+                problems.push(format!("{}: contents differ", path.display()))
+            }
+        }
+        // Anything else would replace what the image beneath has, even a
+        // directory the input only needed created if it was missing
+        for path in actual.keys().filter(|path| !expected.contains_key(*path)) {
+            problems.push(format!("{}: extra", path.display()))
+        }
+        problems.sort();
+        problems
+    });
+    for ((tname, stack), problems) in tnames.iter().zip(&stacks).zip(&results) {
+        let stacknames: Vec<String> = stack.iter().map(|p| p.display().to_string()).collect();
+        if problems.is_empty() {
+            println!("Verified {} against {}", tname, stacknames.join(" -> "))
+        } else {
+            println!("{} differs from {}:", tname, stacknames.join(" -> "));
+            for problem in problems {
+                println!("    {}", problem)
+            }
+        }
+    }
+    tnames.iter().zip(&results).flat_map(|(tname, problems)| {
+        problems.iter().map(move |problem| format!("{}: {}", tname, problem))
+    }).collect()
+}
+
+// Commonise the top layers of the images in a `docker save`, writing the result
+// out as a new save with each image re-parented onto the common layer
+fn commonise_image(savepath: &str, outpath: &str, opts: CommoniseOptions) {
+    assert!(!opts.tree, "--tree is not supported by commonise-image");
+    check_outputs(&[Path::new(outpath)], &[Path::new(savepath)], opts.force);
+    let src = SaveSource::new(savepath);
+    let analysis = save::analyse_save(&src, true);
+    println!("");
+
+    let workdir = WorkDir::create(PathBuf::from(format!("{}.work", outpath)));
+    let layerpaths: Vec<String> = analysis.images.iter().map(|image| {
+        if src.is_tar() {
+            println!("Extracting layer {}", image.id);
+            let layerpath = workdir.path().join(format!("{}.tar", image.id));
+            src.copy_file(&format!("{}/layer.tar", image.id), &layerpath);
+            layerpath.to_str().unwrap().to_owned()
+        } else {
+            image.tarpath.clone()
+        }
+    }).collect();
+    // Naming options are for commonise-tar, the work dir is ours
+    let workopts = CommoniseOptions {
+        outdir: workdir.path().to_path_buf(),
+        contenthash: opts.contenthash,
+        threads: opts.threads,
+        ..CommoniseOptions::default()
+    };
+    let layers = commonise_tars(&to_string_slices(&layerpaths), &workopts);
+
+    println!("Creating image archive {}", outpath);
+    let outar = Archive::new(File::create(outpath).unwrap());
+    let topids: HashSet<&str> = analysis.images.iter().map(|image| &image.id[..]).collect();
+    src.copy_into(&outar, |path| {
+        let toplevel = path.iter().next().and_then(|c| c.to_str()).unwrap_or("");
+        !topids.contains(toplevel) && toplevel != "repositories" && toplevel != "manifest.json"
+    });
+
+    // Layer ids just need to be unique and stable, so derive them from what's
+    // beneath them
+    let commonpath = workopts.common_path();
+    // The common layer comes first, then the individual ones in order
+    let commondiffid = layers[0].diff_id.clone();
+    let parentid = analysis.parent.clone().unwrap_or_else(String::new);
+    let commonid = sha256_hex(&mut io::Cursor::new(format!("{} {}", parentid, commondiffid)));
+    let mut commonjson = json::Object::new();
+    commonjson.insert("id".to_owned(), Json::String(commonid.clone()));
+    if let Some(ref parent) = analysis.parent {
+        commonjson.insert("parent".to_owned(), Json::String(parent.clone()));
+    }
+    commonjson.insert("created".to_owned(), Json::String("1970-01-01T00:00:00Z".to_owned()));
+    let mut containerconfig = json::Object::new();
+    containerconfig.insert("Cmd".to_owned(), Json::Array(vec![
+        Json::String("/bin/sh -c #(nop) ADD file:common.tar in / ".to_owned()),
+    ]));
+    commonjson.insert("container_config".to_owned(), Json::Object(containerconfig));
+    let firstjson = src.read_json(&format!("{}/json", analysis.images[0].id));
+    for key in &["architecture", "os"] {
+        if let Some(val) = firstjson.find(key) {
+            commonjson.insert((*key).to_owned(), val.clone());
+        }
+    }
+    save::append_layer(&outar, &commonid, &Json::Object(commonjson), &commonpath);
+
+    // (new layer id, diff id) for each image
+    let mut newlayers: Vec<(String, String)> = vec![];
+    let mut writtenids = HashSet::new();
+    for (i, image) in analysis.images.iter().enumerate() {
+        let layerpath = workopts.individual_path(i, &layerpaths[i]);
+        let diffid = layers[i + 1].diff_id.clone();
+        let newid = sha256_hex(&mut io::Cursor::new(format!("{} {} {}", commonid, diffid, image.id)));
+        // Keep the original config (env, entrypoint etc) of the image
+        let mut layerjson = src.read_json(&format!("{}/json", image.id));
+        {
+            let layerobj = json_object_mut(&mut layerjson);
+            layerobj.insert("id".to_owned(), Json::String(newid.clone()));
+            layerobj.insert("parent".to_owned(), Json::String(commonid.clone()));
+        }
+        // Multiple tags may point at the same image
+        if writtenids.insert(newid.clone()) {
+            save::append_layer(&outar, &newid, &layerjson, &layerpath);
+        }
+        newlayers.push((newid, diffid));
+    }
+
+    let mut repos: BTreeMap<String, BTreeMap<String, String>> = BTreeMap::new();
+    for (image, &(ref newid, _)) in analysis.images.iter().zip(newlayers.iter()) {
+        repos.entry(image.repo.clone()).or_insert_with(BTreeMap::new).insert(image.tag.clone(), newid.clone());
+    }
+    save::append_data(&outar, "repositories", json::encode(&repos).unwrap().as_bytes());
+
+    // Newer versions of docker load from manifest.json and image configs
+    // rather than the legacy layer json
+    if src.contains_file("manifest.json") {
+        let mut manifest = src.read_json("manifest.json");
+        let mut writtenconfigs = HashSet::new();
+        for item in json_array_mut(&mut manifest).iter_mut() {
+            let toplayer = item.find("Layers").and_then(|l| l.as_array()).and_then(|l| l.last())
+                               .and_then(|l| l.as_string()).unwrap().to_owned();
+            let i = match analysis.images.iter().position(|image| format!("{}/layer.tar", image.id) == toplayer) {
+                Some(i) => i,
+                None => continue,
+            };
+            let (ref newid, ref diffid) = newlayers[i];
+
+            let oldconfigname = item.find("Config").and_then(|c| c.as_string()).unwrap().to_owned();
+            let mut config = src.read_json(&oldconfigname);
+            {
+                let rootfs = json_object_mut(&mut config).get_mut("rootfs").unwrap();
+                let diffids = json_array_mut(json_object_mut(rootfs).get_mut("diff_ids").unwrap());
+                diffids.pop();
+                diffids.push(Json::String(commondiffid.clone()));
+                diffids.push(Json::String(diffid.clone()));
+            }
+            if let Some(history) = json_object_mut(&mut config).get_mut("history") {
+                let history = json_array_mut(history);
+                let toppos = history.iter().rposition(|h| {
+                    h.find("empty_layer").and_then(|e| e.as_boolean()) != Some(true)
+                });
+                if let Some(toppos) = toppos {
+                    let mut commonhistory = json::Object::new();
+                    if let Some(created) = history[toppos].find("created") {
+                        commonhistory.insert("created".to_owned(), created.clone());
+                    }
+                    commonhistory.insert("created_by".to_owned(),
+                                         Json::String("dayer commonise-image".to_owned()));
+                    history.insert(toppos, Json::Object(commonhistory));
+                }
+            }
+            let configstr = config.to_string();
+            let configname = format!("{}.json", sha256_hex(&mut io::Cursor::new(configstr.clone())));
+            if writtenconfigs.insert(configname.clone()) {
+                save::append_data(&outar, &configname, configstr.as_bytes());
+            }
+
+            let itemobj = json_object_mut(item);
+            itemobj.insert("Config".to_owned(), Json::String(configname));
+            let layers = json_array_mut(itemobj.get_mut("Layers").unwrap());
+            layers.pop();
+            layers.push(Json::String(format!("{}/layer.tar", commonid)));
+            layers.push(Json::String(format!("{}/layer.tar", newid)));
+        }
+        save::append_data(&outar, "manifest.json", manifest.to_string().as_bytes());
+    }
+    outar.finish().unwrap();
+
+    println!("Created {}, load it with `docker load -i {}`", outpath, outpath);
+}
+
+// Parse the key="value" parameters of a WWW-Authenticate challenge
+fn parse_auth_params(mut params: &[u8]) -> HashMap<String, String> {
+    let mut parsed = HashMap::new();
+    while !params.is_empty() {
+        let eqpos = params.iter().position(|&b| b == b'=').unwrap();
+        let key = String::from_utf8(params[..eqpos].to_vec()).unwrap();
+        assert!(params[eqpos + 1] == b'"');
+        let valstart = eqpos + 2;
+        let valend = valstart + params.iter().skip(valstart).position(|&b| b == b'"').unwrap();
+        let val = String::from_utf8(params[valstart..valend].to_vec()).unwrap();
+        parsed.insert(key.trim().to_lowercase(), val);
+        if params.len() == valend + 1 { break }
+        assert!(params[valend + 1] == b',');
+        params = &params[valend + 2..];
+    }
+    parsed
+}
+
+// Get a token from the token service in a Bearer challenge, anonymously or
+// with credentials
+// https://docs.docker.com/registry/spec/auth/token/
+// https://docs.docker.com/registry/spec/auth/oauth/
+fn bearer_token(client: &Client, params: &HashMap<String, String>, creds: Option<&Credentials>) -> String {
+    let mut authurl = Url::parse(params.get("realm").expect("No realm in auth challenge")).unwrap();
+    let res = match creds {
+        Some(&Credentials::IdentityToken(ref token)) => {
+            let mut form = HashMap::new();
+            form.insert("grant_type", "refresh_token");
+            form.insert("refresh_token", &token[..]);
+            form.insert("client_id", "dayer");
+            form.insert("service", params.get("service").map_or("", |service| &service[..]));
+            form.insert("scope", params.get("scope").map_or("", |scope| &scope[..]));
+            client.request(Method::Post, authurl.clone()).form(&form).send().unwrap()
+        },
+        _ => {
+            for key in &["service", "scope"] {
+                if let Some(val) = params.get(*key) {
+                    authurl.query_pairs_mut().append_pair(key, val);
+                }
+            }
+            let authreq = client.request(Method::Get, authurl.clone());
+            match creds {
+                Some(&Credentials::Password { ref username, ref password }) => {
+                    let basic = Basic { username: username.clone(), password: Some(password.clone()) };
+                    authreq.header(Authorization(basic)).send().unwrap()
+                },
+                _ => authreq.send().unwrap(),
+            }
+        },
+    };
+    assert!(*res.status() == StatusCode::Ok, "Could not get a token from {}: {}", authurl, res.status());
+    let mut authjson = String::new();
+    BufReader::new(res).read_to_string(&mut authjson).unwrap();
+    // OAuth gives an access_token, the plain token service a token
+    #[derive(RustcDecodable)]
+    struct AuthToken { token: Option<String>, access_token: Option<String> }
+    let authtoken = json::decode::<AuthToken>(&authjson).unwrap();
+    authtoken.token.or(authtoken.access_token).expect("No token from token service")
+}
+
+#[derive(Clone)]
+enum AuthHeader {
+    Basic(Basic),
+    Bearer(Bearer),
+}
+
+impl AuthHeader {
+    fn add_to(&self, req: RequestBuilder) -> RequestBuilder {
+        match *self {
+            AuthHeader::Basic(ref basic) => req.header(Authorization(basic.clone())),
+            AuthHeader::Bearer(ref bearer) => req.header(Authorization(bearer.clone())),
+        }
+    }
+}
+
+// Logging in to a registry - the credentials to use, and the Authorization
+// that last worked for each repository (the scope tokens are given for), so a
+// token is only fetched again when it's refused rather than for every request
+struct RegistryAuth<'a> {
+    creds: Option<&'a Credentials>,
+    headers: RefCell<HashMap<String, AuthHeader>>,
+}
+
+impl<'a> RegistryAuth<'a> {
+    fn new(creds: Option<&'a Credentials>) -> RegistryAuth<'a> {
+        RegistryAuth { creds: creds, headers: RefCell::new(HashMap::new()) }
+    }
+}
+
+// Make a request for something in a repository, logging in if the registry
+// asks - with a password for Basic auth, or with a token from the registry's
+// token service for Bearer auth
+fn req_maybe_auth(client: &Client, method: Method, url: Url, headers: Headers, auth: &RegistryAuth, repo: &str)
+                  -> Response {
+    let cached = auth.headers.borrow().get(repo).cloned();
+    let req = client.request(method.clone(), url.clone()).headers(headers.clone());
+    let res = match cached {
+        Some(ref header) => header.add_to(req),
+        None => req,
+    }.send().unwrap();
+    if *res.status() != StatusCode::Unauthorized {
+        return res
+    }
+    let auth_challenge = res.headers().get_raw("www-authenticate").unwrap();
+    assert!(auth_challenge.len() == 1);
+    let auth_challenge = &auth_challenge[0][..];
+    let schemeend = auth_challenge.iter().position(|&b| b == b' ').unwrap_or(auth_challenge.len());
+    let scheme = str::from_utf8(&auth_challenge[..schemeend]).unwrap().to_lowercase();
+    let params = parse_auth_params(&auth_challenge[schemeend..]);
+    let header = match &scheme[..] {
+        "basic" => match auth.creds {
+            Some(&Credentials::Password { ref username, ref password }) => {
+                AuthHeader::Basic(Basic { username: username.clone(), password: Some(password.clone()) })
+            },
+            _ => panic!("Registry needs a username and password, from `docker login` or --username"),
+        },
+        "bearer" => AuthHeader::Bearer(Bearer { token: bearer_token(client, &params, auth.creds) }),
+        _ => panic!("Unknown auth scheme in challenge {:?}", str::from_utf8(auth_challenge)),
+    };
+    let newres = header.add_to(client.request(method, url.clone()).headers(headers)).send().unwrap();
+    assert!(*newres.status() != StatusCode::Unauthorized, "Registry refused the credentials for {}", url);
+    auth.headers.borrow_mut().insert(repo.to_owned(), header);
+    newres
+}
+
+// Run f on the layer tars of an image in a `docker save`, bottom first. Layers
+// in a tarball are copied out next to outpath while it runs.
+fn with_image_layers<R, F>(savepath: &str, name: &str, outpath: &Path, f: F) -> R
+    where F: FnOnce(&[PathBuf]) -> R
+{
+    let src = SaveSource::new(savepath);
+    let ids = save::image_layer_ids(&src, name);
+    if !src.is_tar() {
+        let layerpaths: Vec<PathBuf> = ids.iter().map(|id| src.extract_dir().join(id).join("layer.tar")).collect();
+        return f(&layerpaths)
+    }
+    let workdir = WorkDir::create(PathBuf::from(format!("{}.work", outpath.display())));
+    let layerpaths: Vec<PathBuf> = ids.iter().map(|id| {
+        println!("Extracting layer {}", id);
+        let layerpath = workdir.path().join(format!("{}.tar", id));
+        src.copy_file(&format!("{}/layer.tar", id), &layerpath);
+        layerpath
+    }).collect();
+    f(&layerpaths)
+}
+
+// The os/arch[/variant] an image is for, as in manifest lists
+#[derive(RustcDecodable)]
+struct Platform {
+    os: String,
+    architecture: String,
+    variant: Option<String>,
+}
+
+impl Platform {
+    fn parse(platform: &str) -> Platform {
+        let parts: Vec<&str> = platform.split('/').collect();
+        assert!(parts.len() == 2 || parts.len() == 3, "Platform {} is not os/arch or os/arch/variant", platform);
+        Platform {
+            os: parts[0].to_owned(),
+            architecture: parts[1].to_owned(),
+            variant: parts.get(2).map(|variant| variant.to_string()),
+        }
+    }
+    // This machine's, named the way Go (and so Docker) names them
+    fn host() -> Platform {
+        let arch = match env::consts::ARCH {
+            "x86_64" => "amd64",
+            "x86" => "386",
+            "aarch64" => "arm64",
+            "powerpc64" if cfg!(target_endian = "little") => "ppc64le",
+            arch => arch,
+        };
+        Platform { os: env::consts::OS.to_owned(), architecture: arch.to_owned(), variant: None }
+    }
+    fn name(&self) -> String {
+        match self.variant {
+            Some(ref variant) => format!("{}/{}/{}", self.os, self.architecture, variant),
+            None => format!("{}/{}", self.os, self.architecture),
+        }
+    }
+    // Like Docker, arm without a variant means v7 and arm64 means v8, whether
+    // it's an image or what was asked for
+    fn variant(&self) -> Option<&str> {
+        match (&self.variant, &self.architecture[..]) {
+            (&Some(ref variant), _) => Some(&variant[..]),
+            (&None, "arm") => Some("v7"),
+            (&None, "arm64") => Some("v8"),
+            (&None, _) => None,
+        }
+    }
+    // Whether an image for the other platform will do, any variant doing if
+    // none was asked for (and there's no default)
+    fn accepts(&self, other: &Platform) -> bool {
+        self.os == other.os && self.architecture == other.architecture &&
+            self.variant().map_or(true, |variant| Some(variant) == other.variant())
+    }
+}
+
+// Pick the digest of the manifest for a platform from a manifest list or OCI
+// index
+fn choose_manifest(listjson: &str, platform: &Platform) -> String {
+    #[derive(RustcDecodable)]
+    struct ManifestDescriptor { digest: String, platform: Option<Platform> }
+    #[derive(RustcDecodable)]
+    struct ManifestList { manifests: Vec<ManifestDescriptor> }
+    let list: ManifestList = json::decode(listjson).unwrap();
+    let chosen = list.manifests.iter().find(|desc| desc.platform.as_ref().map_or(false, |p| platform.accepts(p)));
+    match chosen {
+        Some(desc) => desc.digest.clone(),
+        None => {
+            let available: Vec<String> = list.manifests.iter().filter_map(|desc| desc.platform.as_ref()).map(|p| p.name()).collect();
+            panic!("No image for {}, only for {}", platform.name(), available.join(", "))
+        }
+    }
+}
 
-    println!("Phase 3a: preparing for layer creation");
-    let tonormpath = |h: &HashableHeader| {
-        // Normalise it https://github.com/rust-lang/rust/issues/29008
-        h.0.path().unwrap().components().as_path().to_path_buf()
-    };
-    let commonmap: HashMap<PathBuf, &HashableHeader> = commonfiles.iter()
-                                                                  .map(|h| (tonormpath(h), h))
-                                                                  .collect();
-    println!("Phase 3a complete");
+// Layer media types that download-image can extract. Non-distributable
+// layers may have to be fetched from elsewhere, as listed in the manifest.
+const LAYER_MEDIA_TYPES: &'static [&'static str] = &[
+    "application/vnd.docker.image.rootfs.diff.tar.gzip",
+    "application/vnd.docker.image.rootfs.foreign.diff.tar.gzip",
+    "application/vnd.oci.image.layer.v1.tar",
+    "application/vnd.oci.image.layer.v1.tar+gzip",
+    "application/vnd.oci.image.layer.v1.tar+zstd",
+    "application/vnd.oci.image.layer.nondistributable.v1.tar",
+    "application/vnd.oci.image.layer.nondistributable.v1.tar+gzip",
+    "application/vnd.oci.image.layer.nondistributable.v1.tar+zstd",
+];
 
-    println!("Phase 3b: common layer creation");
-    // Create a holding-place directory for the common layer as it will be
-    // overwritten by the layer above
-    let minimalmkdir = |dirpath: &Path| {
-        let mut newdir = tar::Header::new();
-        newdir.set_path(&dirpath).unwrap();
-        // https://github.com/docker/docker/issues/783
-        newdir.set_mode(0o777);
-        newdir.set_uid(0);
-        newdir.set_gid(0);
-        newdir.set_mtime(0);
-        // cksum: calculated below
-        newdir.link[0] = b'5'; // dir
-        // linkname: irrelevant
-        newdir.set_cksum();
-        newdir
-    };
-    let outname = "common.tar";
-    // It doesn't matter which head map, these are common files!
-    make_layer_tar(outname,
-                   commonfiles.iter(),
-                   vec![].iter_mut(),
-                   arheadmaps.get_mut(0).unwrap(),
-                   &minimalmkdir);
-    println!("Phase 3b complete: created {}", outname);
-
-    println!("Phase 3c: individual layer creation");
-    let thievingmkdir = |dirpath: &Path| commonmap[dirpath].clone().0;
-    for (i, (arheadmap, ignoredfiles)) in arheadmaps.iter_mut()
-                                                    .zip(ignoredfiless.iter_mut())
-                                                    .enumerate() {
-        let outname = format!("individual_{}.tar", i);
-        let outheads: Vec<_> = arheadmap.keys()
-                                        .filter(|h| !commonmap.contains_key(&tonormpath(h)))
-                                        .cloned()
-                                        .collect();
-        make_layer_tar(&outname,
-                       outheads.iter(),
-                       ignoredfiles.iter_mut(),
-                       arheadmap,
-                       &thievingmkdir);
-    }
-    println!("Phase 3c complete: created {} individual tars",
-             arheadmaps.len());
+// Check a manifest asked for by digest is the one that was asked for. Schema 1
+// manifests are signed, and their digest is of the manifest without its
+// signatures, so they're refused rather than trusted.
+fn verify_manifest(manifestjson: &str, reference: &str, schemavsn: usize) {
+    // Tags can't have a colon
+    if !reference.contains(':') {
+        return
+    }
+    assert!(schemavsn != 1, "Manifest {} is schema 1, so can't be checked against its digest", reference);
+    let mut digester = DigestWriter::new(io::sink());
+    digester.write_all(manifestjson.as_bytes()).unwrap();
+    assert!(digester.matches(reference), "Manifest {} doesn't match its digest", reference);
 }
 
-fn req_maybe_bearer_auth(client: &Client, method: Method, url: Url, headers: Headers) -> Response {
-    let res = client.request(Method::Get, url.clone()).headers(headers.clone()).send().unwrap();
-    if *res.status() != StatusCode::Unauthorized {
-        return res
-    }
-    let auth_challenge = res.headers().get_raw("www-authenticate").unwrap();
-    assert!(auth_challenge.len() == 1);
-    let mut auth_challenge = &auth_challenge[0][..];
-    assert!(auth_challenge.starts_with(b"Bearer "));
-    auth_challenge = &auth_challenge[b"Bearer ".len()..];
-    let mut auth_challenge_realm = None;
-    let mut auth_challenge_service = None;
-    let mut auth_challenge_scope = None;
-    loop {
-        let eqpos = auth_challenge.iter().position(|&b| b == b'=').unwrap();
-        let key = &auth_challenge[..eqpos];
-        assert!(auth_challenge[eqpos + 1] == b'"');
-        let valstart = eqpos + 2;
-        let valend = valstart + auth_challenge.iter().skip(valstart).position(|&b| b == b'"').unwrap();
-        let val = String::from_utf8(auth_challenge[valstart..valend].to_vec()).unwrap();
-        match key {
-            b"realm" => auth_challenge_realm = Some(val),
-            b"service" => auth_challenge_service = Some(val),
-            b"scope" => auth_challenge_scope = Some(val),
-            _ => panic!(format!("unknown key in auth challenge {:?}", key)),
-        }
-        if auth_challenge.len() == valend + 1 { break }
-        assert!(auth_challenge[valend + 1] == b',');
-        auth_challenge = &auth_challenge[valend + 2..];
-    }
-    let mut authurl = Url::parse(&auth_challenge_realm.unwrap()).unwrap();
-    authurl.query_pairs_mut()
-        .append_pair("service", &auth_challenge_service.unwrap())
-        .append_pair("scope", &auth_challenge_scope.unwrap());
-    let authreq = client.request(Method::Get, authurl);
-    let mut authjson = String::new();
-    authreq.send().unwrap().read_to_string(&mut authjson).unwrap();
-    #[derive(RustcDecodable)]
-    struct AuthToken { token: String }
-    let authtoken = json::decode::<AuthToken>(&authjson).unwrap().token;
-    let newreq = client.request(method, url).headers(headers).header(Authorization(Bearer { token: authtoken }));
-    newreq.send().unwrap()
+struct LayerBlob {
+    digest: String,
+    mediatype: String,
+    // Where to download it from instead of the registry
+    urls: Vec<String>,
 }
 
-fn download_image(imageurlstr: &str, targetdir: &str) {
+fn download_image(image: &Reference, targetdir: &str, platform: &Platform, creds: Option<&Credentials>) {
     env_logger::init().unwrap();
-    let imageurl = Url::parse(imageurlstr).unwrap();
-    let imagename = imageurl.path();
-    assert!(&imagename[0..1] == "/");
-    let imagetagstart = imagename.bytes().position(|b| b == b':').unwrap();
-    let imagetag = &imagename[imagetagstart+1..];
-    let imagename = &imagename[1..imagetagstart];
-    let mut registryurl = imageurl.clone();
+    let imagename = &image.name[..];
+    let mut registryurl = image.registry.clone();
     registryurl.set_path("v2/");
 
     fs::create_dir(targetdir).unwrap();
 
     let client = &Client::new().unwrap();
+    let auth = &RegistryAuth::new(creds);
 
-    let url = registryurl.join(&format!("{}/manifests/{}", imagename, imagetag)).unwrap();
     // https://docs.docker.com/registry/spec/api/#/pulling-an-image
     // https://docs.docker.com/registry/spec/manifest-v2-1/
     // https://docs.docker.com/registry/spec/manifest-v2-2/
-    // Should really verify manifest
+    // https://github.com/opencontainers/image-spec/blob/master/image-index.md
+    // https://github.com/opencontainers/image-spec/blob/master/manifest.md
     // Ordered from base to top
-    fn manifest_blobs(client: &Client, url: Url) -> Vec<String> {
+    fn manifest_blobs(client: &Client,
+                      auth: &RegistryAuth,
+                      registryurl: &Url,
+                      imagename: &str,
+                      reference: &str,
+                      platform: &Platform)
+                      -> Vec<LayerBlob> {
+        let url = registryurl.join(&format!("{}/manifests/{}", imagename, reference)).unwrap();
         let mut manifestheaders = Headers::new();
         manifestheaders.set(Accept(vec![
             qitem(Mime(TopLevel::Application, SubLevel::Ext("vnd.docker.distribution.manifest.v2+json".to_owned()), vec![])),
+            qitem(Mime(TopLevel::Application, SubLevel::Ext("vnd.docker.distribution.manifest.list.v2+json".to_owned()), vec![])),
+            qitem(Mime(TopLevel::Application, SubLevel::Ext("vnd.oci.image.manifest.v1+json".to_owned()), vec![])),
+            qitem(Mime(TopLevel::Application, SubLevel::Ext("vnd.oci.image.index.v1+json".to_owned()), vec![])),
             qitem(Mime(TopLevel::Application, SubLevel::Ext("vnd.docker.distribution.manifest.v1+json".to_owned()), vec![])),
         ]));
         let mut manifestjson = String::new();
-        req_maybe_bearer_auth(client, Method::Get, url, manifestheaders).read_to_string(&mut manifestjson).unwrap();
+        req_maybe_auth(client, Method::Get, url, manifestheaders, auth, imagename)
+            .read_to_string(&mut manifestjson).unwrap();
         #[allow(non_snake_case)]
         #[derive(RustcDecodable)]
         struct ImageManifestSchemaVersion {
             schemaVersion: usize,
         }
         let schemavsn = json::decode::<ImageManifestSchemaVersion>(&manifestjson).unwrap().schemaVersion;
+        verify_manifest(&manifestjson, reference, schemavsn);
         if schemavsn == 1 {
             #[allow(non_snake_case)]
             #[derive(RustcDecodable)]
@@ -487,42 +1884,71 @@ fn download_image(imageurlstr: &str, targetdir: &str) {
             }
             let manifest: ImageManifest = json::decode(&manifestjson).unwrap();
             // The reverse only happens for v1
-            let mut layers: Vec<_> = manifest.fsLayers.into_iter().map(|fl| fl.blobSum).collect();
+            let mut layers: Vec<_> = manifest.fsLayers.into_iter().map(|fl| LayerBlob {
+                digest: fl.blobSum,
+                mediatype: LAYER_MEDIA_TYPES[0].to_owned(),
+                urls: vec![],
+            }).collect();
             layers.reverse();
             layers
+        } else if schemavsn == 2 && Json::from_str(&manifestjson).unwrap().find("manifests").is_some() {
+            // A manifest list, pointing at a manifest for each platform
+            let digest = choose_manifest(&manifestjson, platform);
+            println!("Using manifest {} for {}", digest, platform.name());
+            manifest_blobs(client, auth, registryurl, imagename, &digest, platform)
         } else if schemavsn == 2 {
+            // Docker and OCI image manifests look the same
             #[allow(non_snake_case)]
             #[derive(RustcDecodable)]
-            struct Layer { digest: String }
+            struct Layer { mediaType: String, digest: String, urls: Option<Vec<String>> }
             #[allow(non_snake_case)]
             #[derive(RustcDecodable)]
             struct ImageManifest {
                 layers: Vec<Layer>,
             }
             let manifest: ImageManifest = json::decode(&manifestjson).unwrap();
-            manifest.layers.into_iter().map(|fl| fl.digest).collect()
+            manifest.layers.into_iter().map(|fl| {
+                assert!(LAYER_MEDIA_TYPES.contains(&&fl.mediaType[..]),
+                        "Layer {} has unsupported media type {}", fl.digest, fl.mediaType);
+                LayerBlob { digest: fl.digest, mediatype: fl.mediaType, urls: fl.urls.unwrap_or(vec![]) }
+            }).collect()
         } else {
             panic!("unknown manifest schemaVersion {}", schemavsn)
         }
     }
-    let blobs: Vec<String> = manifest_blobs(client, url);
+    let layers: Vec<LayerBlob> = manifest_blobs(client, auth, &registryurl, imagename, image.manifest_reference(), platform);
 
-    println!("Found {} blobs", blobs.len());
-    let mut blobheaders = Headers::new();
-    blobheaders.set(Accept(vec![
-        qitem(Mime(TopLevel::Application, SubLevel::Ext("vnd.docker.image.rootfs.diff.tar.gzip".to_owned()), vec![])),
-    ]));
-    for blob in &blobs {
-        println!("Downloading blob {}", blob);
-        let file = File::create(blob).unwrap();
-        let bloburl = registryurl.join(&format!("{}/blobs/{}", imagename, blob)).unwrap();
-        let res = req_maybe_bearer_auth(client, Method::Get, bloburl, blobheaders.clone());
-        io::copy(&mut BufReader::new(res), &mut BufWriter::new(file)).unwrap();
+    println!("Found {} blobs", layers.len());
+    for layer in &layers {
+        println!("Downloading blob {}", layer.digest);
+        let file = File::create(&layer.digest).unwrap();
+        let mut blobheaders = Headers::new();
+        blobheaders.set(Accept(vec![qitem(layer.mediatype.parse().unwrap())]));
+        let res = match layer.urls.first() {
+            Some(url) => client.request(Method::Get, Url::parse(url).unwrap()).headers(blobheaders).send().unwrap(),
+            None => {
+                let bloburl = registryurl.join(&format!("{}/blobs/{}", imagename, layer.digest)).unwrap();
+                req_maybe_auth(client, Method::Get, bloburl, blobheaders, auth, imagename)
+            },
+        };
+        assert!(*res.status() == StatusCode::Ok, "Could not download blob {}: {}", layer.digest, res.status());
+        let mut out = DigestWriter::new(BufWriter::new(file));
+        io::copy(&mut BufReader::new(res), &mut out).unwrap();
+        if !out.matches(&layer.digest) {
+            drop(out);
+            fs::remove_file(&layer.digest).unwrap();
+            panic!("Blob {} doesn't match its digest, it may be corrupt or tampered with", layer.digest)
+        }
     }
+    let blobs: Vec<String> = layers.into_iter().map(|layer| layer.digest).collect();
     for blob in blobs.iter() {
         println!("Extracting blob {}", blob);
-        let exit = Command::new("tar").args(&["--anchored", "--exclude=dev/*", "--force-local", "-C", targetdir, "-xf", blob])
-            .spawn().unwrap().wait().unwrap();
+        // Layers may be compressed with gzip, zstd or not at all
+        let mut tar = Command::new("tar").args(&["--anchored", "--exclude=dev/*", "-C", targetdir, "-xf", "-"])
+            .stdin(Stdio::piped()).spawn().unwrap();
+        io::copy(&mut LayerReader::open(Path::new(blob)), tar.stdin.as_mut().unwrap()).unwrap();
+        drop(tar.stdin.take());
+        let exit = tar.wait().unwrap();
         assert!(exit.success());
         let output = Command::new("find").args(&[targetdir, "-type", "f", "-name", ".wh.*", "-print0"]).output().unwrap();
         assert!(output.status.success());
@@ -561,15 +1987,35 @@ mod tests {
 
     use std::collections::HashMap;
     use std::env::set_current_dir;
+    use std::ffi::OsStr;
     use std::fs;
+    use std::io;
     use std::io::prelude::*;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::{Path, PathBuf};
     use std::sync::Mutex;
 
     use self::tempdir::TempDir;
     use self::DirTreeEntry::*;
+    use super::flate2;
+    use super::flate2::write::GzEncoder;
+    use super::tar;
     use super::tar::Archive;
+    use super::zstd;
+    use super::rustc_serialize::json::Json;
 
-    use super::commonise_tars;
+    use super::auth::{Credentials, config_credentials};
+    use super::{CommoniseOptions, MatchFields, Platform, choose_manifest, commonise_image, commonise_tars,
+                report_commonise, verify_commonise, verify_manifest};
+    use super::compress::{Compression, LayerReader};
+    use super::diff::diff_layer;
+    use super::layer::{HashableHeader, append_entry, walk_layer_entries};
+    use super::overlay::overlay_layers;
+    use super::rebase::rebase_layers;
+    use super::reference::Reference;
+    use super::squash::squash_layers;
+    use super::save::{analyse_save, SaveSource};
+    use super::util::{DigestWriter, sha256_hex};
 
     macro_rules! t {
         ($e:expr) => (match $e {
@@ -651,12 +2097,943 @@ mod tests {
         };
         let outfilelists = hashmap!{
             "common.tar" => vec!["common", "dir"],
-            "individual_0.tar" => vec!["dir", "dir/0"],
-            "individual_1.tar" => vec!["dir", "dir/1"],
+            "individual_0.tar" => vec!["dir/0"],
+            "individual_1.tar" => vec!["dir/1"],
         };
         test_commonise(filetree, infilelists, outfilelists);
     }
 
+    #[test]
+    #[adorn(intmp)]
+    fn whiteouts() {
+        let inar = Archive::new(t!(fs::File::create("in0.tar")));
+        append_test_file(&inar, "d/.wh..wh..opq", "", &[]);
+        append_test_file(&inar, "d/x", "xcontent", &[]);
+        append_test_file(&inar, ".wh.old", "", &[]);
+        t!(inar.finish());
+        let inar = Archive::new(t!(fs::File::create("in1.tar")));
+        append_test_file(&inar, "d/x", "xcontent", &[]);
+        append_test_file(&inar, ".wh.old", "", &[]);
+        t!(inar.finish());
+
+        commonise_tars(&["in0.tar", "in1.tar"], &CommoniseOptions::default());
+        assert!(verify_commonise(&["in0.tar", "in1.tar"], &CommoniseOptions::default()).is_empty());
+
+        // The opaque whiteout would hide d/x if it was shared
+        check_out_tars(vec![
+            ("common.tar", vec![".wh.old"]),
+            ("individual_0.tar", vec!["d/.wh..wh..opq", "d/x"]),
+            ("individual_1.tar", vec!["d/x"]),
+        ]);
+    }
+
+    #[test]
+    #[adorn(intmp)]
+    fn rebase() {
+        let inar = Archive::new(t!(fs::File::create("oldbase.tar")));
+        append_test_file(&inar, "a", "acontent", &[]);
+        append_test_file(&inar, "b", "bcontent", &[]);
+        append_test_file(&inar, "dir/c", "ccontent", &[]);
+        t!(inar.finish());
+        let inar = Archive::new(t!(fs::File::create("image.tar")));
+        append_test_file(&inar, "a", "acontent2", &[]);
+        append_test_file(&inar, "new", "newcontent", &[]);
+        append_test_file(&inar, ".wh.b", "", &[]);
+        t!(inar.finish());
+        let inar = Archive::new(t!(fs::File::create("newbase.tar")));
+        append_test_file(&inar, "a", "apatched", &[]);
+        append_test_file(&inar, "b", "bcontent", &[]);
+        append_test_file(&inar, "dir/c", "cpatched", &[]);
+        append_test_file(&inar, "extra", "extracontent", &[]);
+        t!(inar.finish());
+
+        // Reproduces the image exactly on the new base
+        rebase_layers(&["oldbase.tar", "image.tar"], None, &["newbase.tar"], Path::new("out.tar"), Compression::None);
+        check_out_tars(vec![("out.tar", vec![".wh.b", ".wh.extra", "dir/c", "a", "new"])]);
+        let image = overlay_layers(&["oldbase.tar", "image.tar"][..], false).entries;
+        let rebased = overlay_layers(&["newbase.tar", "out.tar"][..], false).entries;
+        assert!(rebased.keys().collect::<Vec<_>>() == image.keys().collect::<Vec<_>>());
+        assert!(rebased.values().zip(image.values()).all(|(r, i)| r.1.digest == i.1.digest));
+
+        // Keeps what the image didn't change from the new base
+        rebase_layers(&["image.tar"], Some(&["oldbase.tar"][..]), &["newbase.tar"], Path::new("out.tar"), Compression::None);
+        check_out_tars(vec![("out.tar", vec![".wh.b", "a", "new"])]);
+    }
+
+    #[test]
+    #[adorn(intmp)]
+    fn rebase_hard_links() {
+        let inar = Archive::new(t!(fs::File::create("base.tar")));
+        append_test_file(&inar, "a", "aold", &[]);
+        append_test_link(&inar, "l", "a");
+        append_test_file(&inar, "b", "bold", &[]);
+        append_test_link(&inar, "m", "b");
+        t!(inar.finish());
+        let inar = Archive::new(t!(fs::File::create("image.tar")));
+        append_test_file(&inar, "a", "anew", &[]);
+        append_test_file(&inar, "b", "bnew", &[]);
+        append_test_link(&inar, "m", "b");
+        t!(inar.finish());
+
+        rebase_layers(&["base.tar", "image.tar"], None, &["base.tar"], Path::new("out.tar"), Compression::None);
+
+        // l was linked to the old a, so keeps its contents, and m is
+        // unchanged but must come with the b it links to
+        check_out_tars(vec![("out.tar", vec!["l", "a", "b", "m"])]);
+        let rebased = overlay_layers(&["base.tar", "out.tar"][..], false).entries;
+        assert!(rebased[Path::new("l")].1.digest == sha256_hex(&mut "aold".as_bytes()));
+        assert!(rebased[Path::new("m")].0.link_name() == Some(PathBuf::from("b")));
+    }
+
+    #[test]
+    #[adorn(intmp)]
+    fn squash() {
+        let inar = Archive::new(t!(fs::File::create("layer0.tar")));
+        append_test_file(&inar, "a", "aold", &[]);
+        append_test_link(&inar, "l", "a");
+        append_test_link(&inar, "m", "a");
+        append_test_file(&inar, "d/x", "xcontent", &[]);
+        append_test_file(&inar, "gone", "gonecontent", &[]);
+        t!(inar.finish());
+        let inar = Archive::new(t!(fs::File::create("layer1.tar")));
+        append_test_file(&inar, "a", "anew", &[("SCHILY.xattr.user.a", "1")]);
+        append_test_file(&inar, "d/.wh..wh..opq", "", &[]);
+        append_test_file(&inar, "d/z", "zcontent", &[]);
+        append_test_file(&inar, ".wh.gone", "", &[]);
+        append_test_link(&inar, "n", "a");
+        t!(inar.finish());
+
+        squash_layers(&["layer0.tar", "layer1.tar"][..], Path::new("out.tar"), Compression::None);
+
+        // The links made before a was replaced keep its old contents
+        check_out_tars(vec![("out.tar", vec!["l", "m", "a", "d/z", "n"])]);
+        let squashed = overlay_layers(&["out.tar"][..], false).entries;
+        assert!(squashed[Path::new("l")].1.digest == sha256_hex(&mut "aold".as_bytes()));
+        assert!(squashed[Path::new("m")].0.link_name() == Some(PathBuf::from("l")));
+        assert!(squashed[Path::new("n")].0.link_name() == Some(PathBuf::from("a")));
+        assert!((squashed[Path::new("a")].0).1.get("SCHILY.xattr.user.a") == Some(&b"1".to_vec()));
+    }
+
+    #[test]
+    #[adorn(intmp)]
+    fn squash_replaced_links() {
+        let inar = Archive::new(t!(fs::File::create("layer0.tar")));
+        append_test_file(&inar, "a", "aold", &[]);
+        append_test_link(&inar, "l", "a");
+        append_test_link(&inar, "m", "a");
+        append_test_link(&inar, "d/n", "a");
+        t!(inar.finish());
+        let inar = Archive::new(t!(fs::File::create("layer1.tar")));
+        append_test_file(&inar, "a", "anew", &[]);
+        append_test_file(&inar, "l", "lcontent", &[]);
+        append_test_symlink(&inar, "m", "a");
+        append_test_file(&inar, "d", "dcontent", &[]);
+        t!(inar.finish());
+
+        squash_layers(&["layer0.tar", "layer1.tar"][..], Path::new("out.tar"), Compression::None);
+
+        // Nothing still links to the old a, so none of it comes back
+        check_out_tars(vec![("out.tar", vec!["a", "l", "m", "d"])]);
+        let squashed = overlay_layers(&["out.tar"][..], false).entries;
+        assert!(squashed[Path::new("l")].1.digest == sha256_hex(&mut "lcontent".as_bytes()));
+        assert!((squashed[Path::new("m")].0).0.link[0] == b'2');
+        assert!(squashed[Path::new("m")].0.link_name() == Some(PathBuf::from("a")));
+
+        rebase_layers(&["layer0.tar", "layer1.tar"], None, &[], Path::new("out.tar"), Compression::None);
+        check_out_tars(vec![("out.tar", vec!["a", "l", "m", "d"])]);
+    }
+
+    #[test]
+    #[adorn(intmp)]
+    fn diff_dirs() {
+        for dir in &["old", "old/gone", "new"] {
+            t!(fs::create_dir(dir));
+        }
+        for &(path, content) in &[("old/a", "a"), ("old/gone/x", "x"), ("new/a", "a2"), ("new/added", "added"),
+                                  ("new/same", "same"), ("new/unchanged", "unchanged")] {
+            t!(t!(fs::File::create(path)).write_all(content.as_bytes()));
+        }
+        // Linking gives the same metadata in both
+        t!(fs::hard_link("new/same", "old/same"));
+        t!(fs::hard_link("new/unchanged", "old/unchanged"));
+        t!(fs::hard_link("new/same", "new/samelink"));
+
+        diff_layer(Path::new("old"), Path::new("new"), Path::new("out.tar"), Compression::None);
+
+        // The new link needs what it links to
+        check_out_tars(vec![("out.tar", vec![".wh.gone", "a", "added", "same", "samelink"])]);
+        let diffed = overlay_layers(&["out.tar"][..], false).entries;
+        assert!(diffed[Path::new("a")].1.digest == sha256_hex(&mut "a2".as_bytes()));
+        assert!(diffed[Path::new("samelink")].0.link_name() == Some(PathBuf::from("same")));
+    }
+
+    #[test]
+    #[adorn(intmp)]
+    fn diff_tars() {
+        let cap = [("SCHILY.xattr.security.capability", "cap")];
+        let inar = Archive::new(t!(fs::File::create("old.tar")));
+        append_test_file(&inar, "./a", "a", &[]);
+        append_test_file(&inar, "./ping", "ping", &cap);
+        append_test_file(&inar, "./gone", "gone", &[]);
+        t!(inar.finish());
+        // As `tar -C rootfs -cf new.tar .` would make it
+        let inar = Archive::new(t!(fs::File::create("new.tar")));
+        append_test_file(&inar, "a", "a2", &[]);
+        append_test_file(&inar, "ping", "ping", &cap);
+        t!(inar.finish());
+
+        diff_layer(Path::new("old.tar"), Path::new("new.tar"), Path::new("out.tar"), Compression::None);
+
+        check_out_tars(vec![("out.tar", vec![".wh.gone", "a"])]);
+    }
+
+    #[test]
+    #[adorn(intmp)]
+    fn lone_deep_files() {
+        for (i, name) in ["in0.tar", "in1.tar"].iter().enumerate() {
+            let mtime = i.to_string();
+            let inar = Archive::new(t!(fs::File::create(name)));
+            append_test_file(&inar, "common", "commoncontent", &[]);
+            for dir in &["a", "a/b", "a/b/c"] {
+                append_test_dir(&inar, dir, &[("mtime", &mtime)]);
+            }
+            append_test_file(&inar, "a/b/c/tiny", "tinycontent", &[]);
+            append_test_file(&inar, "own", name, &[]);
+            t!(inar.finish());
+        }
+
+        commonise_tars(&["in0.tar", "in1.tar"], &CommoniseOptions::default());
+
+        // Sharing tiny would need three placeholder directories in common.tar,
+        // which would cost more than storing it twice
+        check_out_tars(vec![
+            ("common.tar", vec!["common"]),
+            ("individual_0.tar", vec!["a", "a/b", "a/b/c", "a/b/c/tiny", "own"]),
+            ("individual_1.tar", vec!["a", "a/b", "a/b/c", "a/b/c/tiny", "own"]),
+        ]);
+        let report = report_commonise(&["in0.tar", "in1.tar"], &CommoniseOptions::default(), false);
+        let nearmisses: Vec<(&str, &[String])> =
+            report.near_misses.iter().map(|nm| (&nm.path[..], &nm.reasons[..])).collect();
+        let mtime = &["mtime".to_owned()][..];
+        assert!(nearmisses == vec![("a/b/c/tiny", &["directories".to_owned()][..]),
+                                   ("own", &["contents".to_owned()][..]),
+                                   ("a", mtime),
+                                   ("a/b", mtime),
+                                   ("a/b/c", mtime)]);
+    }
+
+    #[test]
+    #[adorn(intmp)]
+    fn missing_parent_dirs() {
+        for (i, name) in ["in0.tar", "in1.tar"].iter().enumerate() {
+            let inar = Archive::new(t!(fs::File::create(name)));
+            if i == 0 {
+                append_test_dir(&inar, "a", &[("mtime", "1000")]);
+            }
+            append_test_file(&inar, "a/x", "xcontent", &[]);
+            append_test_file(&inar, "b/y", "ycontent", &[]);
+            t!(inar.finish());
+        }
+
+        commonise_tars(&["in0.tar", "in1.tar"], &CommoniseOptions::default());
+        assert!(verify_commonise(&["in0.tar", "in1.tar"], &CommoniseOptions::default()).is_empty());
+
+        // Neither input touches b, or a in in1.tar, so whatever the image
+        // beneath has for them is kept
+        check_out_tars(vec![
+            ("common.tar", vec!["a/x", "b/y"]),
+            ("individual_0.tar", vec!["a"]),
+            ("individual_1.tar", vec![]),
+        ]);
+    }
+
+    #[test]
+    fn manifest_platforms() {
+        let list = r#"{"schemaVersion": 2, "manifests": [
+            {"digest": "sha256:amd64", "platform": {"architecture": "amd64", "os": "linux"}},
+            {"digest": "sha256:armv6", "platform": {"architecture": "arm", "os": "linux", "variant": "v6"}},
+            {"digest": "sha256:armv7", "platform": {"architecture": "arm", "os": "linux", "variant": "v7"}},
+            {"digest": "sha256:arm64", "platform": {"architecture": "arm64", "os": "linux"}},
+            {"digest": "sha256:attestation", "platform": {"architecture": "unknown", "os": "unknown"}}
+        ]}"#;
+        assert!(choose_manifest(list, &Platform::parse("linux/amd64")) == "sha256:amd64");
+        assert!(choose_manifest(list, &Platform::parse("linux/arm")) == "sha256:armv7");
+        assert!(choose_manifest(list, &Platform::parse("linux/arm/v6")) == "sha256:armv6");
+        assert!(choose_manifest(list, &Platform::parse("linux/arm/v7")) == "sha256:armv7");
+        assert!(choose_manifest(list, &Platform::parse("linux/arm64/v8")) == "sha256:arm64");
+
+        // Images without a variant have the default one
+        let list = r#"{"schemaVersion": 2, "manifests": [
+            {"digest": "sha256:armv6", "platform": {"architecture": "arm", "os": "linux", "variant": "v6"}},
+            {"digest": "sha256:arm", "platform": {"architecture": "arm", "os": "linux"}}
+        ]}"#;
+        assert!(choose_manifest(list, &Platform::parse("linux/arm/v7")) == "sha256:arm");
+        assert!(choose_manifest(list, &Platform::parse("linux/arm")) == "sha256:arm");
+    }
+
+    #[test]
+    fn image_references() {
+        let check = |reference: &str, registry: &str, name: &str, manifest: &str| {
+            let parsed = Reference::parse(reference);
+            assert!(parsed.registry.as_str() == registry, "{:?}", parsed);
+            assert!(parsed.name == name && parsed.manifest_reference() == manifest, "{:?}", parsed);
+        };
+        let digest = "sha256:fa2c8cc4f28176bbeed4b736df569a34c79cd3723e9ec42f9674b4d46ac6b8b8";
+        check("ubuntu", "https://registry-1.docker.io/", "library/ubuntu", "latest");
+        check("ubuntu:22.04", "https://registry-1.docker.io/", "library/ubuntu", "22.04");
+        check("docker.io/aidanhs/dayer", "https://registry-1.docker.io/", "aidanhs/dayer", "latest");
+        check("quay.io/coreos/etcd", "https://quay.io/", "coreos/etcd", "latest");
+        check(&format!("localhost:5000/foo/bar@{}", digest), "http://localhost:5000/", "foo/bar", digest);
+        check(&format!("ubuntu:22.04@{}", digest), "https://registry-1.docker.io/", "library/ubuntu", digest);
+        check("https://registry-1.docker.io/library/ubuntu:latest",
+              "https://registry-1.docker.io/", "library/ubuntu", "latest");
+        check("http://myregistry:8080/team/app:v2", "http://myregistry:8080/", "team/app", "v2");
+    }
+
+    #[test]
+    #[should_panic(expected = "is schema 1")]
+    fn schema1_by_digest() {
+        let manifest = r#"{"schemaVersion": 1, "fsLayers": [{"blobSum": "sha256:aaa"}]}"#;
+        // Fine by tag, as there's nothing to check against
+        verify_manifest(manifest, "latest", 1);
+        verify_manifest(manifest, "sha256:fa2c8cc4f28176bbeed4b736df569a34c79cd3723e9ec42f9674b4d46ac6b8b8", 1);
+    }
+
+    #[test]
+    #[adorn(intmp)]
+    fn docker_config_credentials() {
+        let config = r#"{"auths": {
+            "https://index.docker.io/v1/": {"auth": "dXNlcjpwYXNz"},
+            "quay.io": {"auth": "", "identitytoken": "refreshtoken"},
+            "localhost:5000": {}
+        }}"#;
+        t!(t!(fs::File::create("config.json")).write_all(config.as_bytes()));
+        let configpath = Path::new("config.json");
+
+        match config_credentials(configpath, "registry-1.docker.io") {
+            Some(Credentials::Password { ref username, ref password }) => assert!(username == "user" && password == "pass"),
+            _ => panic!("no password for the Docker Hub"),
+        }
+        match config_credentials(configpath, "quay.io") {
+            Some(Credentials::IdentityToken(ref token)) => assert!(token == "refreshtoken"),
+            _ => panic!("no identity token for quay.io"),
+        }
+        assert!(config_credentials(configpath, "localhost:5000").is_none());
+        assert!(config_credentials(configpath, "gcr.io").is_none());
+        assert!(config_credentials(Path::new("missing.json"), "gcr.io").is_none());
+    }
+
+    #[test]
+    fn digest_check() {
+        let mut digester = DigestWriter::new(io::sink());
+        t!(digester.write_all(b"blob"));
+        assert!(digester.matches("sha256:fa2c8cc4f28176bbeed4b736df569a34c79cd3723e9ec42f9674b4d46ac6b8b8"));
+        assert!(!digester.matches("sha256:0000000000000000000000000000000000000000000000000000000000000000"));
+        assert!(digester.matches("sha512:78dfec637347c509fe8d78d55efbd28fa1666f146e3a964b7eda2551e6f36bf2\
+                                  9b57266584fa47306ba7332e246e9d4406c5a72a9e609e2eca6e36a6ff505f36"));
+    }
+
+    fn append_test_file(ar: &Archive<fs::File>, path: &str, content: &str, records: &[(&str, &str)]) {
+        let mut hheader = HashableHeader::new(&tar::Header::new());
+        hheader.0.set_mode(0o644);
+        hheader.0.set_uid(0);
+        hheader.0.set_gid(0);
+        hheader.0.set_mtime(0);
+        hheader.0.set_size(content.len() as u64);
+        hheader.0.link[0] = b'0';
+        for &(key, val) in records {
+            hheader.1.insert(key.to_owned(), val.as_bytes().to_vec());
+        }
+        hheader.set_path(Path::new(path));
+        append_entry(ar, &hheader, &mut content.as_bytes());
+    }
+
+    fn append_test_dir(ar: &Archive<fs::File>, path: &str, records: &[(&str, &str)]) {
+        let mut hheader = HashableHeader::new(&tar::Header::new());
+        hheader.0.set_mode(0o755);
+        hheader.0.set_uid(0);
+        hheader.0.set_gid(0);
+        hheader.0.set_mtime(0);
+        hheader.0.set_size(0);
+        hheader.0.link[0] = b'5';
+        for &(key, val) in records {
+            hheader.1.insert(key.to_owned(), val.as_bytes().to_vec());
+        }
+        hheader.set_path(Path::new(path));
+        append_entry(ar, &hheader, &mut io::empty());
+    }
+
+    fn append_test_global(ar: &Archive<fs::File>, records: &[(&str, &str)]) {
+        let mut data = vec![];
+        for &(key, val) in records {
+            let record = format!(" {}={}\n", key, val);
+            let len = record.len() + 2;
+            assert!(len >= 10 && len < 100);
+            data.extend_from_slice(format!("{}{}", len, record).as_bytes());
+        }
+        let mut header = tar::Header::new();
+        t!(header.set_path("pax_global_header"));
+        header.set_mode(0o644);
+        header.set_size(data.len() as u64);
+        header.link[0] = b'g';
+        header.set_cksum();
+        t!(ar.append(&header, &mut &data[..]));
+    }
+
+    fn append_test_gnu_longname(ar: &Archive<fs::File>, path: &str, content: &str) {
+        let mut longname = path.as_bytes().to_vec();
+        longname.push(0);
+        let mut header = tar::Header::new();
+        t!(header.set_path("././@LongLink"));
+        header.set_mode(0o644);
+        header.set_size(longname.len() as u64);
+        header.link[0] = b'L';
+        header.set_cksum();
+        t!(ar.append(&header, &mut &longname[..]));
+
+        let mut header = tar::Header::new();
+        header.name[..100].copy_from_slice(&path.as_bytes()[..100]);
+        header.set_mode(0o644);
+        header.set_size(content.len() as u64);
+        header.link[0] = b'0';
+        header.set_cksum();
+        t!(ar.append(&header, &mut content.as_bytes()));
+    }
+
+    fn append_test_link(ar: &Archive<fs::File>, path: &str, target: &str) {
+        append_test_linkentry(ar, b'1', path, target)
+    }
+
+    fn append_test_symlink(ar: &Archive<fs::File>, path: &str, target: &str) {
+        append_test_linkentry(ar, b'2', path, target)
+    }
+
+    fn append_test_linkentry(ar: &Archive<fs::File>, kind: u8, path: &str, target: &str) {
+        let mut header = tar::Header::new();
+        t!(header.set_path(path));
+        t!(header.set_link_name(target));
+        header.set_mode(0o644);
+        header.set_size(0);
+        header.link[0] = kind;
+        header.set_cksum();
+        t!(ar.append(&header, &mut io::empty()));
+    }
+
+    fn read_layer_entries(file: &mut fs::File) -> Vec<(HashableHeader, u64)> {
+        let mut entries = vec![];
+        walk_layer_entries(file, |hheader, offset, _| entries.push((hheader, offset)));
+        entries
+    }
+
+    fn check_out_tars(outfilelists: Vec<(&str, Vec<&str>)>) {
+        for (outname, outfilelist) in outfilelists {
+            let entries = read_layer_entries(&mut t!(fs::File::open(outname)));
+            let paths: Vec<_> = entries.iter().map(|&(ref h, _)| h.path()).collect();
+            assert!(paths == outfilelist.iter().map(|p| Path::new(p).to_path_buf()).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn binary_pax_records() {
+        let mut hheader = HashableHeader::new(&tar::Header::new());
+        hheader.1.insert("hdrcharset".to_owned(), b"BINARY".to_vec());
+        hheader.1.insert("path".to_owned(), b"dir/\xffname".to_vec());
+        hheader.1.insert("linkpath".to_owned(), b"\xfe".to_vec());
+        hheader.1.insert("uname".to_owned(), b"us\xffer".to_vec());
+        assert!(hheader.path().as_os_str().as_bytes() == b"dir/\xffname");
+        assert!(hheader.link_name().unwrap().as_os_str().as_bytes() == b"\xfe");
+        assert!(hheader.uname() == "us\u{fffd}er");
+
+        // ...and are written back the same
+        let longpath = PathBuf::from(OsStr::from_bytes(&[0xff; 150][..]));
+        hheader.set_path(&longpath);
+        assert!(hheader.path() == longpath);
+    }
+
+    #[test]
+    #[adorn(intmp)]
+    fn extended_headers() {
+        let longpath = format!("dir/{}", "x".repeat(150));
+        let inar = Archive::new(t!(fs::File::create("in0.tar")));
+        append_test_file(&inar, &longpath, "longcontent", &[]);
+        append_test_file(&inar, "xattr", "xattrcontent", &[("SCHILY.xattr.user.foo", "bar")]);
+        append_test_file(&inar, "diffxattr", "xattrcontent", &[("SCHILY.xattr.user.foo", "bar")]);
+        t!(inar.finish());
+        let inar = Archive::new(t!(fs::File::create("in1.tar")));
+        append_test_file(&inar, &longpath, "longcontent", &[]);
+        append_test_file(&inar, "xattr", "xattrcontent", &[("SCHILY.xattr.user.foo", "bar")]);
+        append_test_file(&inar, "diffxattr", "xattrcontent", &[("SCHILY.xattr.user.foo", "baz")]);
+        t!(inar.finish());
+
+        commonise_tars(&["in0.tar", "in1.tar"], &CommoniseOptions::default());
+
+        check_out_tars(vec![
+            ("common.tar", vec![&longpath[..], "xattr"]),
+            ("individual_0.tar", vec!["diffxattr"]),
+            ("individual_1.tar", vec!["diffxattr"]),
+        ]);
+    }
+
+    #[test]
+    #[adorn(intmp)]
+    fn global_extended_headers() {
+        let inar = Archive::new(t!(fs::File::create("in0.tar")));
+        append_test_global(&inar, &[("comment", "abc123"), ("uname", "someone")]);
+        append_test_file(&inar, "common", "commoncontent", &[]);
+        append_test_file(&inar, "unset", "unsetcontent", &[("uname", "")]);
+        t!(inar.finish());
+        let inar = Archive::new(t!(fs::File::create("in1.tar")));
+        append_test_global(&inar, &[("comment", "def456")]);
+        append_test_file(&inar, "common", "commoncontent", &[("uname", "someone")]);
+        append_test_file(&inar, "unset", "unsetcontent", &[]);
+        t!(inar.finish());
+
+        commonise_tars(&["in0.tar", "in1.tar"], &CommoniseOptions::default());
+
+        check_out_tars(vec![
+            ("common.tar", vec!["common", "unset"]),
+            ("individual_0.tar", vec![]),
+            ("individual_1.tar", vec![]),
+        ]);
+        // The global record has to come along with the entry
+        let entries = read_layer_entries(&mut t!(fs::File::open("common.tar")));
+        assert!(entries[0].0.uname() == "someone");
+    }
+
+    #[test]
+    #[adorn(intmp)]
+    fn gnu_long_names() {
+        let longpath = format!("dir/{}", "x".repeat(150));
+        let otherlongpath = format!("dir/{}", "y".repeat(150));
+        let inar = Archive::new(t!(fs::File::create("in0.tar")));
+        append_test_gnu_longname(&inar, &longpath, "longcontent");
+        append_test_gnu_longname(&inar, &otherlongpath, "othercontent0");
+        t!(inar.finish());
+        let inar = Archive::new(t!(fs::File::create("in1.tar")));
+        append_test_gnu_longname(&inar, &longpath, "longcontent");
+        append_test_gnu_longname(&inar, &otherlongpath, "othercontent1");
+        t!(inar.finish());
+
+        commonise_tars(&["in0.tar", "in1.tar"], &CommoniseOptions::default());
+
+        check_out_tars(vec![
+            ("common.tar", vec![&longpath[..]]),
+            ("individual_0.tar", vec![&otherlongpath[..]]),
+            ("individual_1.tar", vec![&otherlongpath[..]]),
+        ]);
+    }
+
+    #[test]
+    #[adorn(intmp)]
+    fn hard_links() {
+        let inar = Archive::new(t!(fs::File::create("in0.tar")));
+        append_test_file(&inar, "x", "xcontent", &[]);
+        append_test_link(&inar, "c", "x");
+        append_test_file(&inar, "y", "ycontent0", &[]);
+        append_test_link(&inar, "b", "y");
+        append_test_file(&inar, "z", "zcontent", &[]);
+        append_test_link(&inar, "a", "z");
+        t!(inar.finish());
+        let inar = Archive::new(t!(fs::File::create("in1.tar")));
+        append_test_file(&inar, "x", "xcontent", &[]);
+        append_test_file(&inar, "y", "ycontent1", &[]);
+        append_test_link(&inar, "b", "y");
+        append_test_file(&inar, "z", "zcontent", &[]);
+        append_test_link(&inar, "a", "z");
+        t!(inar.finish());
+
+        commonise_tars(&["in0.tar", "in1.tar"], &CommoniseOptions::default());
+
+        // x has a link in one tar and not the other, and the link b and its
+        // target y are identical but the content differs
+        check_out_tars(vec![
+            ("common.tar", vec!["z", "a"]),
+            ("individual_0.tar", vec!["x", "c", "y", "b"]),
+            ("individual_1.tar", vec!["x", "y", "b"]),
+        ]);
+        // Links must come after their target
+        let entries = read_layer_entries(&mut t!(fs::File::open("common.tar")));
+        assert!(!entries[0].0.is_hard_link() && entries[0].0.size() == 8);
+        assert!(entries[1].0.is_hard_link() && entries[1].0.link_name() == Some(Path::new("z").to_path_buf()));
+    }
+
+    #[test]
+    #[adorn(intmp)]
+    fn content_hash() {
+        let inar = Archive::new(t!(fs::File::create("in0.tar")));
+        append_test_file(&inar, "diff", "diffcontent0", &[]);
+        append_test_file(&inar, "owned", "ownedcontent", &[("uid", "1000")]);
+        append_test_file(&inar, "retimed", "retimedcontent", &[("mtime", "1000.5")]);
+        t!(inar.finish());
+        let inar = Archive::new(t!(fs::File::create("in1.tar")));
+        append_test_file(&inar, "diff", "diffcontent1", &[]);
+        append_test_file(&inar, "owned", "ownedcontent", &[("uid", "1001")]);
+        append_test_file(&inar, "retimed", "retimedcontent", &[("mtime", "2000")]);
+        t!(inar.finish());
+
+        let opts = CommoniseOptions {
+            contenthash: Some(MatchFields::parse("mode,xattrs")),
+            ..CommoniseOptions::default()
+        };
+        commonise_tars(&["in0.tar", "in1.tar"], &opts);
+        assert!(verify_commonise(&["in0.tar", "in1.tar"], &opts).is_empty());
+
+        check_out_tars(vec![
+            ("common.tar", vec!["owned", "retimed"]),
+            ("individual_0.tar", vec!["diff"]),
+            ("individual_1.tar", vec!["diff"]),
+        ]);
+        // Unmatched metadata is cleared rather than taken from the first tar
+        let entries = read_layer_entries(&mut t!(fs::File::open("common.tar")));
+        assert!(entries[0].0.uid() == 0);
+        assert!(entries[1].0.mtime() == "0");
+    }
+
+    #[test]
+    #[adorn(intmp)]
+    fn content_hash_match() {
+        let inar = Archive::new(t!(fs::File::create("in0.tar")));
+        append_test_dir(&inar, "p", &[]);
+        append_test_symlink(&inar, "s", "a");
+        append_test_file(&inar, "x", "xcontent", &[("SCHILY.xattr.user.a", "0"), ("uid", "1000")]);
+        t!(inar.finish());
+        let inar = Archive::new(t!(fs::File::create("in1.tar")));
+        append_test_file(&inar, "p", "", &[]);
+        append_test_symlink(&inar, "s", "b");
+        append_test_file(&inar, "x", "xcontent", &[("SCHILY.xattr.user.a", "1"), ("uid", "1001")]);
+        t!(inar.finish());
+
+        let opts = CommoniseOptions {
+            contenthash: Some(MatchFields::parse("mode")),
+            ..CommoniseOptions::default()
+        };
+        commonise_tars(&["in0.tar", "in1.tar"], &opts);
+        assert!(verify_commonise(&["in0.tar", "in1.tar"], &opts).is_empty());
+
+        // Entry types and link targets are matched whatever --match says
+        check_out_tars(vec![
+            ("common.tar", vec!["x"]),
+            ("individual_0.tar", vec!["p", "s"]),
+            ("individual_1.tar", vec!["p", "s"]),
+        ]);
+        let entries = read_layer_entries(&mut t!(fs::File::open("common.tar")));
+        assert!(entries[0].0.xattrs().is_empty());
+        assert!(entries[0].0.uid() == 0);
+    }
+
+    #[test]
+    #[adorn(intmp)]
+    fn layer_tree() {
+        for (i, name) in ["in0.tar", "in1.tar", "in2.tar"].iter().enumerate() {
+            let inar = Archive::new(t!(fs::File::create(name)));
+            append_test_file(&inar, "all", "allcontent", &[]);
+            if i < 2 {
+                append_test_file(&inar, "pair", "paircontent", &[]);
+            }
+            append_test_file(&inar, "own", &format!("owncontent{}", i), &[]);
+            t!(inar.finish());
+        }
+
+        let opts = CommoniseOptions { tree: true, ..CommoniseOptions::default() };
+        commonise_tars(&["in0.tar", "in1.tar", "in2.tar"], &opts);
+        assert!(verify_commonise(&["in0.tar", "in1.tar", "in2.tar"], &opts).is_empty());
+
+        check_out_tars(vec![
+            ("common.tar", vec!["all"]),
+            ("shared_0.tar", vec!["pair"]),
+            ("individual_0.tar", vec!["own"]),
+            ("individual_1.tar", vec!["own"]),
+            ("individual_2.tar", vec!["own"]),
+        ]);
+        let mut graph = String::new();
+        t!(t!(fs::File::open("common.layers.json")).read_to_string(&mut graph));
+        assert!(graph.contains(r#"{"name":"shared_0.tar","parent":"common.tar","inputs":["in0.tar","in1.tar"]"#));
+    }
+
+    fn make_simple_tars() {
+        for name in &["in0.tar", "in1.tar"] {
+            let inar = Archive::new(t!(fs::File::create(name)));
+            append_test_file(&inar, "common", "commoncontent", &[]);
+            append_test_file(&inar, "own", name, &[]);
+            t!(inar.finish());
+        }
+    }
+
+    #[test]
+    #[adorn(intmp)]
+    fn output_names() {
+        make_simple_tars();
+        let opts = CommoniseOptions {
+            outdir: Path::new("out").to_path_buf(),
+            commonname: "base.tar".to_owned(),
+            nametemplate: "{stem}-top.tar".to_owned(),
+            ..CommoniseOptions::default()
+        };
+        commonise_tars(&["in0.tar", "in1.tar"], &opts);
+
+        check_out_tars(vec![
+            ("out/base.tar", vec!["common"]),
+            ("out/in0-top.tar", vec!["own"]),
+            ("out/in1-top.tar", vec!["own"]),
+        ]);
+        // Running again is fine if asked
+        commonise_tars(&["in0.tar", "in1.tar"], &CommoniseOptions { force: true, ..opts });
+    }
+
+    #[test]
+    #[adorn(intmp)]
+    fn verify_mismatch() {
+        for name in &["in0.tar", "in1.tar"] {
+            let inar = Archive::new(t!(fs::File::create(name)));
+            append_test_file(&inar, "common", "commoncontent", &[]);
+            append_test_file(&inar, "d/own", name, &[]);
+            t!(inar.finish());
+        }
+        commonise_tars(&["in0.tar", "in1.tar"], &CommoniseOptions::default());
+        assert!(verify_commonise(&["in0.tar", "in1.tar"], &CommoniseOptions::default()).is_empty());
+
+        // in0.tar doesn't have d, so writing it would replace the image's own
+        let outar = Archive::new(t!(fs::File::create("individual_0.tar")));
+        append_test_dir(&outar, "d", &[]);
+        append_test_file(&outar, "d/own", "in1.tar", &[]);
+        append_test_file(&outar, "extra", "extracontent", &[]);
+        t!(outar.finish());
+        let problems = verify_commonise(&["in0.tar", "in1.tar"], &CommoniseOptions::default());
+        assert!(problems == vec!["in0.tar: d/own: contents differ",
+                                 "in0.tar: d: extra",
+                                 "in0.tar: extra: extra"]);
+    }
+
+    #[test]
+    #[adorn(intmp)]
+    fn dry_run_report() {
+        make_simple_tars();
+        let report = report_commonise(&["in0.tar", "in1.tar"], &CommoniseOptions::default(), false);
+
+        assert!((report.candidate_files, report.candidate_bytes) == (2, 20));
+        assert!((report.common_files, report.common_bytes) == (1, 13));
+        assert!(report.saved_bytes == 13);
+        assert!(report.top_dirs.len() == 1);
+        assert!((&report.top_dirs[0].dir[..], report.top_dirs[0].files) == (".", 1));
+        assert!(report.near_misses.len() == 1);
+        assert!(report.near_misses[0].path == "own");
+        assert!(report.near_misses[0].sizes == vec![7, 7]);
+        assert!(report.near_misses[0].reasons == vec!["contents"]);
+        assert!(!Path::new("common.tar").exists());
+    }
+
+    #[test]
+    #[adorn(intmp)]
+    fn near_miss_diagnostics() {
+        let inar = Archive::new(t!(fs::File::create("in0.tar")));
+        append_test_file(&inar, "retimed0", "content", &[("mtime", "1000")]);
+        append_test_file(&inar, "retimed1", "content", &[("mtime", "1000")]);
+        append_test_file(&inar, "owned", "ownedcontent0", &[("uid", "1000")]);
+        t!(inar.finish());
+        let inar = Archive::new(t!(fs::File::create("in1.tar")));
+        append_test_file(&inar, "retimed0", "content", &[("mtime", "2000")]);
+        append_test_file(&inar, "retimed1", "content", &[("mtime", "2000")]);
+        append_test_file(&inar, "owned", "ownedcontent1", &[("uid", "1001")]);
+        t!(inar.finish());
+
+        let report = report_commonise(&["in0.tar", "in1.tar"], &CommoniseOptions::default(), false);
+        let groups: Vec<(Vec<String>, usize)> =
+            report.near_miss_groups.iter().map(|g| (g.reasons.clone(), g.files)).collect();
+        assert!(groups == vec![(vec!["mtime".to_owned()], 2),
+                               (vec!["uid".to_owned(), "contents".to_owned()], 1)]);
+        // Unmatched fields don't count with --content-hash
+        let opts = CommoniseOptions {
+            contenthash: Some(MatchFields::parse("mode")),
+            ..CommoniseOptions::default()
+        };
+        let report = report_commonise(&["in0.tar", "in1.tar"], &opts, false);
+        assert!(report.near_misses.len() == 1);
+        assert!(report.near_misses[0].reasons == vec!["contents"]);
+    }
+
+    #[test]
+    #[adorn(intmp)]
+    fn compressed_layers() {
+        make_simple_tars();
+        let mut encoder = GzEncoder::new(t!(fs::File::create("in0.tar.gz")), flate2::Compression::Default);
+        t!(io::copy(&mut t!(fs::File::open("in0.tar")), &mut encoder));
+        t!(encoder.finish());
+        let mut encoder = t!(zstd::stream::Encoder::new(t!(fs::File::create("in1.tar.zst")), 3));
+        t!(io::copy(&mut t!(fs::File::open("in1.tar")), &mut encoder));
+        t!(encoder.finish());
+
+        let opts = CommoniseOptions { compression: Compression::Gzip, ..CommoniseOptions::default() };
+        let layers = commonise_tars(&["in0.tar.gz", "in1.tar.zst"], &opts);
+
+        let paths = |name: &str| {
+            let mut paths = vec![];
+            walk_layer_entries(&mut LayerReader::open(Path::new(name)), |h, _, _| paths.push(h.path()));
+            paths
+        };
+        assert!(paths("common.tar.gz") == vec![PathBuf::from("common")]);
+        assert!(paths("individual_0.tar.gz") == vec![PathBuf::from("own")]);
+        assert!(paths("individual_1.tar.gz") == vec![PathBuf::from("own")]);
+        // The digest is of the file as written, the diff id of the tar in it
+        let digest = format!("sha256:{}", sha256_hex(&mut t!(fs::File::open("common.tar.gz"))));
+        assert!(layers[0].name == "common.tar.gz");
+        assert!(layers[0].digest == digest && layers[0].diff_id != digest);
+    }
+
+    #[test]
+    #[should_panic(expected = "already exists")]
+    #[adorn(intmp)]
+    fn no_overwrite() {
+        make_simple_tars();
+        t!(fs::File::create("individual_1.tar"));
+        commonise_tars(&["in0.tar", "in1.tar"], &CommoniseOptions::default());
+    }
+
+    #[test]
+    #[should_panic(expected = "in0.tar is an input")]
+    #[adorn(intmp)]
+    fn no_overwrite_input() {
+        make_simple_tars();
+        let opts = CommoniseOptions {
+            nametemplate: "./in{index}.tar".to_owned(),
+            force: true,
+            ..CommoniseOptions::default()
+        };
+        commonise_tars(&["in0.tar", "in1.tar"], &opts);
+    }
+
+    #[test]
+    #[adorn(intmp)]
+    fn analyse_save_dir() {
+        let savefiles = vec![
+            ("repositories", r#"{"img2":{"latest":"bbb"},"img1":{"latest":"aaa","v1":"ccc"}}"#),
+            ("aaa/VERSION", "1.0"),
+            ("aaa/json", r#"{"id":"aaa","parent":"ppp"}"#),
+            ("aaa/layer.tar", ""),
+            ("bbb/VERSION", "1.0"),
+            ("bbb/json", r#"{"id":"bbb","parent":"ppp"}"#),
+            ("bbb/layer.tar", ""),
+            ("ccc/VERSION", "1.0"),
+            ("ccc/json", r#"{"id":"ccc","parent":"ppp"}"#),
+            ("ccc/layer.tar", ""),
+        ];
+        for &(name, content) in &savefiles {
+            let path = Path::new("save").join(name);
+            t!(fs::create_dir_all(path.parent().unwrap()));
+            let mut f = t!(fs::File::create(path));
+            t!(f.write_all(content.as_bytes()))
+        }
+
+        let analysis = analyse_save(&SaveSource::new("save"), false);
+        assert!(analysis.parent == Some("ppp".to_owned()));
+        let repotags: Vec<_> = analysis.images.iter().map(|image| image.repotag()).collect();
+        assert!(repotags == vec!["img1:latest", "img1:v1", "img2:latest"]);
+        let tarpaths: Vec<_> = analysis.images.iter().map(|image| &image.tarpath[..]).collect();
+        assert!(tarpaths == vec!["save/aaa/layer.tar", "save/ccc/layer.tar", "save/bbb/layer.tar"]);
+
+        // ...and the same from a tarball of it
+        let outar = Archive::new(t!(fs::File::create("save.tar")));
+        SaveSource::new("save").copy_into(&outar, |_| true);
+        t!(outar.finish());
+        let src = SaveSource::new("save.tar");
+        let taranalysis = analyse_save(&src, false);
+        assert!(taranalysis.images.iter().map(|image| image.repotag()).collect::<Vec<_>>() == repotags);
+        assert!(src.read_file("bbb/json") == Some(br#"{"id":"bbb","parent":"ppp"}"#.to_vec()));
+        assert!(src.read_file("bbb").is_none() && !src.contains_file("bbb") && !src.contains_file("ddd/json"));
+        src.copy_file("ccc/json", Path::new("ccc.json"));
+        let mut copied = String::new();
+        t!(t!(fs::File::open("ccc.json")).read_to_string(&mut copied));
+        assert!(copied == r#"{"id":"ccc","parent":"ppp"}"#);
+    }
+
+    #[test]
+    #[adorn(intmp)]
+    fn commonise_image_save() {
+        let config = |id: &str| format!(r#"{{"architecture": "amd64", "os": "linux",
+            "rootfs": {{"type": "layers", "diff_ids": ["sha256:base", "sha256:{0}"]}},
+            "history": [{{"created": "t0", "created_by": "base"}}, {{"created": "t1", "created_by": "add {0}"}},
+                        {{"created": "t2", "created_by": "env", "empty_layer": true}}]}}"#, id);
+        let savefiles = vec![
+            ("repositories", r#"{"img1":{"latest":"aaa"},"img2":{"latest":"bbb"}}"#.to_owned()),
+            ("manifest.json", r#"[{"Config": "a.json", "RepoTags": ["img1:latest"], "Layers": ["ppp/layer.tar", "aaa/layer.tar"]},
+                                 {"Config": "b.json", "RepoTags": ["img2:latest"], "Layers": ["ppp/layer.tar", "bbb/layer.tar"]}]"#.to_owned()),
+            ("a.json", config("aaa")),
+            ("b.json", config("bbb")),
+            ("ppp/VERSION", "1.0".to_owned()),
+            ("ppp/json", r#"{"id":"ppp"}"#.to_owned()),
+            ("ppp/layer.tar", "".to_owned()),
+            ("aaa/VERSION", "1.0".to_owned()),
+            ("aaa/json", r#"{"id":"aaa","parent":"ppp","config":{"Cmd":["a"]}}"#.to_owned()),
+            ("bbb/VERSION", "1.0".to_owned()),
+            ("bbb/json", r#"{"id":"bbb","parent":"ppp","config":{"Cmd":["b"]}}"#.to_owned()),
+        ];
+        for &(name, ref content) in &savefiles {
+            let path = Path::new("save").join(name);
+            t!(fs::create_dir_all(path.parent().unwrap()));
+            t!(t!(fs::File::create(path)).write_all(content.as_bytes()));
+        }
+        for &(id, own) in &[("aaa", "a"), ("bbb", "b")] {
+            let inar = Archive::new(t!(fs::File::create(format!("save/{}/layer.tar", id))));
+            append_test_file(&inar, "common", "commoncontent", &[]);
+            append_test_file(&inar, "own", own, &[]);
+            t!(inar.finish());
+        }
+
+        commonise_image("save", "out.tar", CommoniseOptions::default());
+        assert!(!Path::new("out.tar.work").exists());
+
+        let mut files: HashMap<String, Vec<u8>> = HashMap::new();
+        let outar = Archive::new(t!(fs::File::open("out.tar")));
+        for entry in t!(outar.entries()) {
+            let mut entry = t!(entry);
+            let path = t!(entry.header().path()).to_str().unwrap().to_owned();
+            let mut data = vec![];
+            t!(entry.read_to_end(&mut data));
+            files.insert(path, data);
+        }
+        let json = |name: &str| Json::from_str(&String::from_utf8(files[name].clone()).unwrap()).unwrap();
+        let string = |val: Option<&Json>| val.and_then(|val| val.as_string()).unwrap().to_owned();
+        let layerfile = |id: &str| format!("{}/layer.tar", id);
+        let layerpaths = |id: &str| {
+            let mut paths = vec![];
+            walk_layer_entries(&mut &files[&layerfile(id)][..], |h, _, _| paths.push(h.path()));
+            paths
+        };
+        let diffid = |id: &str| format!("sha256:{}", sha256_hex(&mut &files[&layerfile(id)][..]));
+
+        // Each image is re-parented onto the common layer, which goes on the
+        // old parent
+        let repos = json("repositories");
+        let ids = vec![string(repos.find_path(&["img1", "latest"])), string(repos.find_path(&["img2", "latest"]))];
+        let commonid = string(json(&format!("{}/json", ids[0])).find("parent"));
+        assert!(string(json(&format!("{}/json", ids[1])).find("parent")) == commonid);
+        assert!(string(json(&format!("{}/json", commonid)).find("parent")) == "ppp");
+        assert!(string(json(&format!("{}/json", ids[0])).find_path(&["config", "Cmd"]).and_then(|c| c.as_array())
+                                                         .and_then(|c| c.first())) == "a");
+        for id in ids.iter().chain(Some(&commonid)) {
+            assert!(files[&format!("{}/VERSION", id)] == b"1.0");
+        }
+        assert!(files.contains_key("ppp/json") && !files.contains_key("aaa/json") && !files.contains_key("bbb/json"));
+        assert!(layerpaths(&commonid) == vec![PathBuf::from("common")]);
+        assert!(layerpaths(&ids[0]) == vec![PathBuf::from("own")]);
+        assert!(layerpaths(&ids[1]) == vec![PathBuf::from("own")]);
+
+        // ...and the manifest and configs say the same
+        let manifest = json("manifest.json");
+        for (item, (id, oldid)) in manifest.as_array().unwrap().iter().zip(ids.iter().zip(&["aaa", "bbb"])) {
+            let layers: Vec<String> = item.find("Layers").unwrap().as_array().unwrap().iter()
+                                          .map(|layer| string(Some(layer))).collect();
+            assert!(layers == vec!["ppp/layer.tar".to_owned(), layerfile(&commonid), layerfile(id)]);
+            let config = json(&string(item.find("Config")));
+            let diffids: Vec<String> = config.find_path(&["rootfs", "diff_ids"]).unwrap().as_array().unwrap().iter()
+                                             .map(|diffid| string(Some(diffid))).collect();
+            assert!(diffids == vec!["sha256:base".to_owned(), diffid(&commonid), diffid(id)]);
+            let history: Vec<String> = config.find("history").unwrap().as_array().unwrap().iter()
+                                             .map(|h| string(h.find("created_by"))).collect();
+            assert!(history == vec!["base".to_owned(), "dayer commonise-image".to_owned(),
+                                    format!("add {}", oldid), "env".to_owned()]);
+        }
+    }
+
     fn test_commonise(filetree: HashMap<&str, DirTreeEntry>,
                       infilelists: HashMap<&str, Vec<&str>>,
                       outfilelists: HashMap<&str, Vec<&str>>) {
@@ -685,7 +3062,8 @@ mod tests {
 
         let mut infilenames: Vec<_> = infilelists.keys().map(|s| *s).collect();
         infilenames.sort();
-        commonise_tars(&infilenames[..]);
+        commonise_tars(&infilenames[..], &CommoniseOptions::default());
+        assert!(verify_commonise(&infilenames[..], &CommoniseOptions::default()).is_empty());
 
         for (outname, outfilelist) in outfilelists.iter() {
             let outfile = t!(fs::File::open(outname));
@@ -0,0 +1,212 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use tar;
+use tar::Archive;
+
+use compress::{Compression, LayerDigests, LayerReader, LayerWriter};
+use layer::{HashableHeader, WHITEOUT_PREFIX, append_entry, normpath, parent_dirs, walk_layer_entries};
+use util::sha256_hex;
+
+// The filesystem left by extracting layers one on top of another, the way
+// Docker does - whiteouts remove what they hide from lower layers, and a
+// non-directory replacing a directory takes the directory's contents with it.
+// Each entry carries a value, e.g. where its data can be found.
+pub struct Overlay<T> {
+    pub entries: BTreeMap<PathBuf, (HashableHeader, T)>,
+    // What each hard link pointed at when it was made, which may since have
+    // been replaced or removed
+    pub linktargets: HashMap<PathBuf, (HashableHeader, T)>,
+    // Paths added by the layer being applied, which its opaque whiteouts don't
+    // hide
+    layerpaths: HashSet<PathBuf>,
+    // Keep whiteouts as entries, rather than only applying them
+    keepwhiteouts: bool,
+}
+
+impl<T: Clone> Overlay<T> {
+    pub fn new(keepwhiteouts: bool) -> Overlay<T> {
+        Overlay {
+            entries: BTreeMap::new(),
+            linktargets: HashMap::new(),
+            layerpaths: HashSet::new(),
+            keepwhiteouts: keepwhiteouts,
+        }
+    }
+
+    // Entries applied after this are from the next layer up
+    pub fn next_layer(&mut self) {
+        self.layerpaths.clear()
+    }
+
+    pub fn apply(&mut self, hheader: HashableHeader, value: T) {
+        let path = normpath(&hheader.path());
+        let removed = if let Some(dir) = hheader.opaque_dir() {
+            let layerpaths = &self.layerpaths;
+            remove_beneath(&mut self.entries, &dir, |p| !layerpaths.contains(p))
+        } else if let Some(target) = hheader.whiteout_target() {
+            self.entries.remove(&target);
+            let mut removed = remove_beneath(&mut self.entries, &target, |_| true);
+            removed.push(target);
+            removed
+        } else if !hheader.is_dir() {
+            remove_beneath(&mut self.entries, &path, |_| true)
+        } else {
+            vec![]
+        };
+        // A link that's removed or replaced no longer holds on to anything
+        for removedpath in removed {
+            self.linktargets.remove(&removedpath);
+        }
+        if hheader.is_whiteout() && !self.keepwhiteouts {
+            return
+        }
+        self.linktargets.remove(&path);
+        if hheader.is_hard_link() {
+            let target = normpath(&hheader.link_name().unwrap());
+            if let Some(entry) = self.entries.get(&target).cloned() {
+                self.linktargets.insert(path.clone(), entry);
+            }
+        }
+        self.layerpaths.insert(path.clone());
+        self.entries.insert(path, (hheader, value));
+    }
+}
+
+// Remove entries beneath dir, returning the paths removed
+fn remove_beneath<T, F>(entries: &mut BTreeMap<PathBuf, (HashableHeader, T)>, dir: &Path, shouldremove: F)
+                        -> Vec<PathBuf>
+    where F: Fn(&Path) -> bool
+{
+    // Paths compare by component, so everything beneath dir follows it
+    let beneath: Vec<PathBuf> = entries.range(dir.to_path_buf()..)
+                                       .map(|(p, _)| p)
+                                       .take_while(|p| p.starts_with(dir))
+                                       .filter(|p| p.as_path() != dir && shouldremove(p))
+                                       .cloned()
+                                       .collect();
+    for path in &beneath {
+        entries.remove(path);
+    }
+    beneath
+}
+
+// Where an entry's data is - which layer, and where in it
+#[derive(Clone)]
+pub struct EntrySource {
+    pub layer: usize,
+    pub offset: u64,
+    pub digest: String,
+}
+
+impl Overlay<EntrySource> {
+    // The entries, except that a hard link whose target has since been replaced
+    // or removed still has the old contents, so gets them back as a file.
+    // Later links to the same old file link to that.
+    pub fn resolve_links(self) -> BTreeMap<PathBuf, (HashableHeader, EntrySource)> {
+        let mut copies: HashMap<PathBuf, (HashableHeader, EntrySource)> = HashMap::new();
+        let mut copied: HashMap<(usize, u64), PathBuf> = HashMap::new();
+        for (path, &(ref hheader, _)) in &self.entries {
+            if !hheader.is_hard_link() {
+                continue
+            }
+            let linked = match self.linktargets.get(path) {
+                Some(linked) => linked,
+                None => continue,
+            };
+            let target = normpath(&hheader.link_name().unwrap());
+            let current = self.entries.get(&target);
+            if current.map_or(false, |c| (c.1.layer, c.1.offset) == (linked.1.layer, linked.1.offset)) {
+                continue
+            }
+            let key = (linked.1.layer, linked.1.offset);
+            if let Some(copypath) = copied.get(&key) {
+                let mut link = hheader.clone();
+                link.set_link_name(copypath);
+                copies.insert(path.clone(), (link, self.entries[path].1.clone()));
+                continue
+            }
+            let mut copy = linked.0.clone();
+            copy.set_path(path);
+            copies.insert(path.clone(), (copy, linked.1.clone()));
+            copied.insert(key, path.clone());
+        }
+        let mut entries = self.entries;
+        entries.extend(copies);
+        entries
+    }
+}
+
+// Apply layer tars one on top of another, from the bottom up
+pub fn overlay_layers<P: AsRef<Path>>(tpaths: &[P], keepwhiteouts: bool) -> Overlay<EntrySource> {
+    let mut overlay = Overlay::new(keepwhiteouts);
+    for (i, tpath) in tpaths.iter().enumerate() {
+        walk_layer_entries(&mut LayerReader::open(tpath.as_ref()), |hheader, offset, data| {
+            let source = EntrySource { layer: i, offset: offset, digest: sha256_hex(data) };
+            overlay.apply(hheader, source);
+        });
+        overlay.next_layer();
+    }
+    overlay
+}
+
+fn whiteout(target: &Path) -> HashableHeader {
+    let mut hheader = HashableHeader::new(&tar::Header::new());
+    hheader.0.set_mode(0o644);
+    hheader.0.set_uid(0);
+    hheader.0.set_gid(0);
+    hheader.0.set_mtime(0);
+    hheader.0.set_size(0);
+    hheader.0.link[0] = b'0';
+    let name = format!("{}{}", WHITEOUT_PREFIX, target.file_name().unwrap().to_str().unwrap());
+    hheader.set_path(&target.with_file_name(name));
+    hheader
+}
+
+// Whiteouts for paths (in order) that a layer of entries removes from beneath
+// it, leaving out any already gone with a directory that's removed or replaced
+// by a non-directory
+pub fn whiteouts<T>(removed: &[&Path], entries: &BTreeMap<PathBuf, (HashableHeader, T)>) -> Vec<HashableHeader> {
+    let mut whiteouts = vec![];
+    let mut gonedirs: HashSet<&Path> = HashSet::new();
+    for &path in removed {
+        let gone = parent_dirs(path).iter().any(|dir| {
+            gonedirs.contains(&dir.as_path()) || entries.get(dir).map_or(false, |&(ref h, _)| !h.is_dir())
+        });
+        if !gone {
+            whiteouts.push(whiteout(path));
+        }
+        gonedirs.insert(path);
+    }
+    whiteouts
+}
+
+// Write a single layer of whiteouts followed by entries from the layers they
+// came from. Entries are written in the order they were in the layers, so each
+// layer only needs reading from front to back.
+pub fn write_layer<P: AsRef<Path>>(outpath: &Path,
+                                   compression: Compression,
+                                   tpaths: &[P],
+                                   whiteouts: &[HashableHeader],
+                                   entries: &[&(HashableHeader, EntrySource)])
+                                   -> LayerDigests {
+    let outar = Archive::new(LayerWriter::create(outpath, compression));
+    for whiteout in whiteouts {
+        append_entry(&outar, whiteout, &mut io::empty());
+    }
+    let mut entries = entries.to_vec();
+    entries.sort_by_key(|&&(_, ref source)| (source.layer, source.offset));
+    let mut reader: Option<(usize, LayerReader)> = None;
+    for &&(ref hheader, ref source) in &entries {
+        if reader.as_ref().map_or(true, |&(layer, _)| layer != source.layer) {
+            reader = Some((source.layer, LayerReader::open(tpaths[source.layer].as_ref())))
+        }
+        let archive = &mut reader.as_mut().unwrap().1;
+        archive.skip_to(source.offset);
+        append_entry(&outar, hheader, &mut (&mut *archive).take(hheader.size()));
+    }
+    outar.finish().unwrap();
+    outar.into_inner().finish()
+}
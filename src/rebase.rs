@@ -0,0 +1,89 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+use compress::{Compression, LayerDigests};
+use layer::{HashableHeader, normpath, parent_dirs};
+use overlay::{EntrySource, overlay_layers, whiteouts, write_layer};
+
+type Entries = BTreeMap<PathBuf, (HashableHeader, EntrySource)>;
+
+fn same_entry(entry1: &(HashableHeader, EntrySource), entry2: &(HashableHeader, EntrySource)) -> bool {
+    entry1.0 == entry2.0 && entry1.1.digest == entry2.1.digest
+}
+
+// Make a layer which turns the filesystem of the new base into the one the
+// image's layers give, so the image can be moved onto the new base. Layers are
+// listed bottom first, the image's layers going on top of the old base's.
+//
+// Without the old base, the result reproduces the image's filesystem exactly.
+// With it, anything the image's own layers didn't change from the old base is
+// left to the new base instead - so updates in the new base are kept.
+pub fn rebase_layers(tnames: &[&str],
+                     fromnames: Option<&[&str]>,
+                     ontonames: &[&str],
+                     outpath: &Path,
+                     compression: Compression)
+                     -> LayerDigests {
+    let imagenames: Vec<&str> = fromnames.unwrap_or(&[]).iter().chain(tnames).cloned().collect();
+    println!("Reading {} image layers", imagenames.len());
+    // Links left with an old file's contents are files of their own
+    let image: Entries = overlay_layers(&imagenames[..], false).resolve_links();
+    let oldbase: Option<Entries> = fromnames.map(|fromnames| {
+        println!("Reading {} old base layers", fromnames.len());
+        overlay_layers(fromnames, false).entries
+    });
+    println!("Reading {} new base layers", ontonames.len());
+    let newbase: Entries = overlay_layers(ontonames, false).entries;
+
+    let unchanged = |path: &Path, entry: &(HashableHeader, EntrySource)| {
+        oldbase.as_ref().map_or(false, |oldbase| oldbase.get(path).map_or(false, |old| same_entry(old, entry)))
+    };
+    let mut keep: BTreeSet<PathBuf> =
+        image.iter()
+             .filter(|&(path, entry)| {
+                 !newbase.get(path).map_or(false, |new| same_entry(new, entry)) && !unchanged(path, entry)
+             })
+             .map(|(path, _)| path.clone())
+             .collect();
+    // Hard links must have their target in the same layer, and links to a
+    // kept file must come with it or they'd keep the new base's contents
+    loop {
+        let mut linked = vec![];
+        for (path, &(ref hheader, _)) in &image {
+            if !hheader.is_hard_link() {
+                continue
+            }
+            let target = normpath(&hheader.link_name().unwrap());
+            if keep.contains(path) && !keep.contains(&target) && image.contains_key(&target) {
+                linked.push(target)
+            } else if keep.contains(&target) && !keep.contains(path) {
+                linked.push(path.clone())
+            }
+        }
+        if linked.is_empty() {
+            break
+        }
+        keep.extend(linked);
+    }
+    // ...and anything kept needs its directories, if the new base doesn't
+    // have them
+    let dirs: Vec<PathBuf> = keep.iter()
+                                 .flat_map(|path| parent_dirs(path))
+                                 .filter(|dir| image.contains_key(dir) && !newbase.contains_key(dir))
+                                 .collect();
+    keep.extend(dirs);
+
+    // Remove anything in the new base that the image doesn't have
+    let removed: Vec<&Path> = newbase.keys()
+                                     .filter(|path| {
+                                         !image.contains_key(*path) &&
+                                             oldbase.as_ref().map_or(true, |oldbase| oldbase.contains_key(*path))
+                                     })
+                                     .map(|path| path.as_path())
+                                     .collect();
+    let whiteouts = whiteouts(&removed, &image);
+
+    let entries: Vec<&(HashableHeader, EntrySource)> = keep.iter().map(|path| &image[path]).collect();
+    println!("Writing {} entries and {} whiteouts to {}", entries.len(), whiteouts.len(), outpath.display());
+    write_layer(outpath, compression, &imagenames[..], &whiteouts, &entries)
+}
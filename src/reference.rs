@@ -0,0 +1,83 @@
+use reqwest::Url;
+
+const DOCKER_HUB: &'static str = "registry-1.docker.io";
+
+// An image in a registry, from a reference like Docker takes (`ubuntu`,
+// `quay.io/coreos/etcd:v3.1`, `localhost:5000/foo@sha256:<hex>`) or a url
+// like `https://registry-1.docker.io/library/ubuntu:latest`
+#[derive(Debug)]
+pub struct Reference {
+    pub registry: Url,
+    pub name: String,
+    pub tag: String,
+    pub digest: Option<String>,
+}
+
+// The first part of a name is a registry if it looks like a hostname
+fn is_registry(part: &str) -> bool {
+    part.contains('.') || part.contains(':') || part == "localhost"
+}
+
+// Like Docker, registries on this machine are assumed not to have TLS
+fn is_local(host: &str) -> bool {
+    let hostname = host.split(':').next().unwrap();
+    hostname == "localhost" || hostname.starts_with("127.")
+}
+
+impl Reference {
+    pub fn parse(reference: &str) -> Reference {
+        let (registry, rest) = if let Some(schemeend) = reference.find("://") {
+            let namestart = reference[schemeend + 3..].find('/')
+                                                      .unwrap_or_else(|| panic!("No image name in {}", reference));
+            let namestart = schemeend + 3 + namestart;
+            (reference[..namestart].to_owned(), &reference[namestart + 1..])
+        } else {
+            match reference.find('/') {
+                Some(i) if is_registry(&reference[..i]) => {
+                    let host = &reference[..i];
+                    let host = if host == "docker.io" || host == "index.docker.io" { DOCKER_HUB } else { host };
+                    let scheme = if is_local(host) { "http" } else { "https" };
+                    (format!("{}://{}", scheme, host), &reference[i + 1..])
+                },
+                _ => (format!("https://{}", DOCKER_HUB), reference),
+            }
+        };
+        let registry = Url::parse(&registry).unwrap_or_else(|e| panic!("Invalid registry in {}: {}", reference, e));
+
+        // A tag alongside a digest is only for show
+        let (rest, digest) = match rest.find('@') {
+            Some(i) => (&rest[..i], Some(rest[i + 1..].to_owned())),
+            None => (rest, None),
+        };
+        let (name, tag) = match rest.rfind(':') {
+            Some(i) if !rest[i..].contains('/') => (&rest[..i], &rest[i + 1..]),
+            _ => (rest, "latest"),
+        };
+        assert!(!name.is_empty() && !tag.is_empty(), "Invalid image reference {}", reference);
+        assert!(name.chars().all(|c| c.is_lowercase() || c.is_digit(10) || "._-/".contains(c)),
+                "Invalid image name {}, names are lowercase letters, digits and separators", name);
+        if let Some(ref digest) = digest {
+            assert!(digest.contains(':'), "Invalid digest {}, expected algorithm:hex", digest);
+        }
+        // Official images on the Docker Hub live under library/
+        let name = if registry.host_str() == Some(DOCKER_HUB) && !name.contains('/') {
+            format!("library/{}", name)
+        } else {
+            name.to_owned()
+        };
+        Reference { registry: registry, name: name, tag: tag.to_owned(), digest: digest }
+    }
+
+    // host[:port] of the registry, as credentials are saved under
+    pub fn registry_host(&self) -> String {
+        match self.registry.port() {
+            Some(port) => format!("{}:{}", self.registry.host_str().unwrap(), port),
+            None => self.registry.host_str().unwrap().to_owned(),
+        }
+    }
+
+    // What to ask the registry for the manifest of
+    pub fn manifest_reference(&self) -> &str {
+        self.digest.as_ref().unwrap_or(&self.tag)
+    }
+}
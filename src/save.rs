@@ -0,0 +1,315 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::io::BufReader;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::str;
+
+use rustc_serialize::json;
+use rustc_serialize::json::Json;
+
+use tar;
+use tar::Archive;
+
+use layer::walk_layer_entries;
+
+// The output of `docker save`, either extracted into a directory or still in
+// the tarball it was written to
+pub enum SaveSource {
+    Dir(PathBuf),
+    // With the (offset, size) of the data of each file in it, so files can be
+    // read without scanning the whole tarball each time
+    Tar(PathBuf, HashMap<PathBuf, (u64, u64)>),
+}
+
+impl SaveSource {
+    pub fn new(savepath: &str) -> SaveSource {
+        let path = PathBuf::from(savepath);
+        let meta = fs::metadata(&path).unwrap_or_else(|e| panic!("Could not open {}: {}", savepath, e));
+        if meta.is_dir() {
+            return SaveSource::Dir(path)
+        }
+        let mut index = HashMap::new();
+        walk_layer_entries(&mut BufReader::new(fs::File::open(&path).unwrap()), |hheader, offset, _| {
+            if !hheader.is_dir() {
+                index.insert(normpath(&hheader.path()), (offset, hheader.size()));
+            }
+        });
+        SaveSource::Tar(path, index)
+    }
+
+    // A file in the tarball, ready to read
+    fn open_tar_file(tarpath: &Path, index: &HashMap<PathBuf, (u64, u64)>, name: &str) -> Option<io::Take<fs::File>> {
+        index.get(Path::new(name)).map(|&(offset, size)| {
+            let mut file = fs::File::open(tarpath).unwrap();
+            file.seek(io::SeekFrom::Start(offset)).unwrap();
+            file.take(size)
+        })
+    }
+
+    pub fn is_tar(&self) -> bool {
+        match *self {
+            SaveSource::Dir(_) => false,
+            SaveSource::Tar(..) => true,
+        }
+    }
+
+    // The directory the save is (or should be) extracted into - for a
+    // tarball this is the tarball path without its extension
+    pub fn extract_dir(&self) -> PathBuf {
+        match *self {
+            SaveSource::Dir(ref dir) => dir.clone(),
+            SaveSource::Tar(ref tarpath, _) => tarpath.with_extension(""),
+        }
+    }
+
+    pub fn read_file(&self, name: &str) -> Option<Vec<u8>> {
+        let mut data = vec![];
+        match *self {
+            SaveSource::Dir(ref dir) => {
+                let mut file = match fs::File::open(dir.join(name)) {
+                    Ok(file) => file,
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => return None,
+                    Err(e) => panic!("Could not open {}: {}", name, e),
+                };
+                file.read_to_end(&mut data).unwrap();
+            },
+            SaveSource::Tar(ref tarpath, ref index) => {
+                match SaveSource::open_tar_file(tarpath, index, name) {
+                    Some(mut file) => file.read_to_end(&mut data).unwrap(),
+                    None => return None,
+                };
+            },
+        }
+        Some(data)
+    }
+
+    pub fn read_json(&self, name: &str) -> Json {
+        let data = self.read_file(name).unwrap_or_else(|| panic!("{} missing", name));
+        Json::from_str(str::from_utf8(&data).unwrap()).unwrap()
+    }
+
+    // Copy a file out of the save, e.g. to get a layer tar on disk
+    pub fn copy_file(&self, name: &str, dest: &Path) {
+        match *self {
+            SaveSource::Dir(ref dir) => {
+                fs::copy(dir.join(name), dest).unwrap();
+            },
+            SaveSource::Tar(ref tarpath, ref index) => {
+                let mut file = SaveSource::open_tar_file(tarpath, index, name)
+                                   .unwrap_or_else(|| panic!("Could not find {} in save", name));
+                io::copy(&mut file, &mut fs::File::create(dest).unwrap()).unwrap();
+            },
+        }
+    }
+
+    // Copy everything in the save into a new archive, apart from any paths
+    // rejected by keep
+    pub fn copy_into<W: Write, F: Fn(&Path) -> bool>(&self, outar: &Archive<W>, keep: F) {
+        match *self {
+            SaveSource::Dir(ref dir) => copy_dir_into(dir, dir, outar, &keep),
+            SaveSource::Tar(ref tarpath, _) => {
+                let ar = Archive::new(fs::File::open(tarpath).unwrap());
+                for entry in ar.entries().unwrap() {
+                    let mut entry = entry.unwrap();
+                    let header = entry.header().clone();
+                    if !keep(&normpath(&header.path().unwrap())) {
+                        continue
+                    }
+                    outar.append(&header, &mut entry).unwrap();
+                }
+            },
+        }
+    }
+
+    pub fn contains_file(&self, name: &str) -> bool {
+        match *self {
+            SaveSource::Dir(ref dir) => dir.join(name).is_file(),
+            SaveSource::Tar(_, ref index) => index.contains_key(Path::new(name)),
+        }
+    }
+}
+
+fn copy_dir_into<W: Write, F: Fn(&Path) -> bool>(root: &Path, dir: &Path, outar: &Archive<W>, keep: &F) {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir).unwrap().map(|e| e.unwrap().path()).collect();
+    paths.sort();
+    for path in paths {
+        let name = path.strip_prefix(root).unwrap().to_path_buf();
+        if !keep(&name) {
+            continue
+        }
+        if path.is_dir() {
+            outar.append(&new_header(&name, 0, b'5'), &mut io::empty()).unwrap();
+            copy_dir_into(root, &path, outar, keep)
+        } else {
+            let mut file = fs::File::open(&path).unwrap();
+            let size = file.metadata().unwrap().len();
+            outar.append(&new_header(&name, size, b'0'), &mut file).unwrap();
+        }
+    }
+}
+
+// Headers for the plain files and directories that make up a save
+fn new_header(name: &Path, size: u64, ftype: u8) -> tar::Header {
+    let mut header = tar::Header::new();
+    header.set_path(name).unwrap();
+    header.set_size(size);
+    header.set_mode(if ftype == b'5' { 0o755 } else { 0o644 });
+    header.set_uid(0);
+    header.set_gid(0);
+    header.set_mtime(0);
+    header.link[0] = ftype;
+    header.set_cksum();
+    header
+}
+
+pub fn append_data<W: Write>(outar: &Archive<W>, name: &str, data: &[u8]) {
+    let header = new_header(Path::new(name), data.len() as u64, b'0');
+    outar.append(&header, &mut &data[..]).unwrap();
+}
+
+// Add a layer in the legacy `<id>/{VERSION,json,layer.tar}` layout
+pub fn append_layer<W: Write>(outar: &Archive<W>, id: &str, layerjson: &Json, layertar: &Path) {
+    outar.append(&new_header(Path::new(id), 0, b'5'), &mut io::empty()).unwrap();
+    append_data(outar, &format!("{}/VERSION", id), b"1.0");
+    append_data(outar, &format!("{}/json", id), layerjson.to_string().as_bytes());
+    let mut file = fs::File::open(layertar).unwrap();
+    let size = file.metadata().unwrap().len();
+    let header = new_header(&Path::new(id).join("layer.tar"), size, b'0');
+    outar.append(&header, &mut file).unwrap();
+}
+
+pub fn json_object_mut(val: &mut Json) -> &mut json::Object {
+    match *val {
+        Json::Object(ref mut obj) => obj,
+        _ => panic!("expected json object"),
+    }
+}
+
+pub fn json_array_mut(val: &mut Json) -> &mut json::Array {
+    match *val {
+        Json::Array(ref mut arr) => arr,
+        _ => panic!("expected json array"),
+    }
+}
+
+// Normalise it https://github.com/rust-lang/rust/issues/29008
+fn normpath(path: &Path) -> PathBuf {
+    path.components().as_path().to_path_buf()
+}
+
+#[derive(RustcEncodable)]
+pub struct SaveImage {
+    pub repo: String,
+    pub tag: String,
+    pub id: String,
+    pub tarpath: String,
+}
+
+impl SaveImage {
+    pub fn repotag(&self) -> String {
+        format!("{}:{}", self.repo, self.tag)
+    }
+}
+
+#[derive(RustcEncodable)]
+pub struct SaveAnalysis {
+    pub images: Vec<SaveImage>,
+    // None means scratch
+    pub parent: Option<String>,
+}
+
+// Ported from the `analyse` operation of the old commonise.sh
+pub fn analyse_save(src: &SaveSource, verbose: bool) -> SaveAnalysis {
+    let reposdata = src.read_file("repositories").expect("Could not find repositories json");
+    let reposjson = String::from_utf8(reposdata).unwrap();
+    // BTreeMap gives us the repos and tags in a stable order
+    let repos: BTreeMap<String, BTreeMap<String, String>> = json::decode(&reposjson).unwrap();
+
+    if verbose { println!("Identifying top layers from repositories") }
+    let extractdir = src.extract_dir();
+    let mut images = vec![];
+    for (repo, tags) in repos {
+        if verbose { println!("Repo: {}", repo) }
+        for (tag, layerid) in tags {
+            if verbose { println!("    Tag: {}, ID: {}", tag, layerid) }
+            let tarpath = extractdir.join(&layerid).join("layer.tar");
+            images.push(SaveImage {
+                repo: repo.clone(),
+                tag: tag,
+                id: layerid,
+                tarpath: tarpath.to_str().unwrap().to_owned(),
+            })
+        }
+    }
+    assert!(!images.is_empty(), "No images found in repositories json");
+
+    if verbose {
+        println!("");
+        println!("Checking parent layer ids");
+    }
+    #[derive(RustcDecodable)]
+    struct LayerJson { parent: Option<String> }
+    let mut commonparent = None;
+    for (i, image) in images.iter().enumerate() {
+        let version = src.read_file(&format!("{}/VERSION", image.id))
+                         .unwrap_or_else(|| panic!("{}/VERSION missing", image.id));
+        assert!(str::from_utf8(&version).unwrap().trim() == "1.0",
+                "{}/VERSION is not 1.0", image.id);
+        let layerjson = src.read_file(&format!("{}/json", image.id))
+                           .unwrap_or_else(|| panic!("{}/json missing", image.id));
+        let layerjson = String::from_utf8(layerjson).unwrap();
+        let parent = json::decode::<LayerJson>(&layerjson).unwrap().parent;
+        if i == 0 {
+            commonparent = parent
+        } else {
+            assert!(commonparent == parent, "Parent mismatch for {}", image.id)
+        }
+    }
+    if verbose {
+        println!("All layers have a common parent: {}",
+                 commonparent.as_ref().map_or("scratch", |p| &p[..]));
+    }
+
+    for image in &images {
+        let layername = format!("{}/layer.tar", image.id);
+        assert!(src.contains_file(&layername),
+                "Could not find layer tar at {}", image.tarpath);
+    }
+
+    SaveAnalysis {
+        images: images,
+        parent: commonparent,
+    }
+}
+
+// Layer ids of an image, bottom first. The image is a repo:tag (the tag
+// defaulting to latest) or the id of its top layer.
+pub fn image_layer_ids(src: &SaveSource, name: &str) -> Vec<String> {
+    let reposdata = src.read_file("repositories").expect("Could not find repositories json");
+    let reposjson = String::from_utf8(reposdata).unwrap();
+    let repos: BTreeMap<String, BTreeMap<String, String>> = json::decode(&reposjson).unwrap();
+    let (repo, tag) = match name.rfind(':') {
+        Some(i) if !name[i..].contains('/') => (&name[..i], &name[i+1..]),
+        _ => (name, "latest"),
+    };
+    let mut id = repos.get(repo).and_then(|tags| tags.get(tag)).cloned().unwrap_or_else(|| name.to_owned());
+
+    #[derive(RustcDecodable)]
+    struct LayerJson { parent: Option<String> }
+    let mut ids = vec![];
+    loop {
+        let layerjson = src.read_file(&format!("{}/json", id))
+                           .unwrap_or_else(|| panic!("No image or layer {} in save", id));
+        let layerjson = String::from_utf8(layerjson).unwrap();
+        let parent = json::decode::<LayerJson>(&layerjson).unwrap().parent;
+        ids.push(id);
+        match parent {
+            Some(parent) => id = parent,
+            None => break,
+        }
+    }
+    ids.reverse();
+    ids
+}
@@ -0,0 +1,17 @@
+use std::path::Path;
+
+use compress::{Compression, LayerDigests};
+use layer::HashableHeader;
+use overlay::{EntrySource, overlay_layers, write_layer};
+
+// Flatten layers, bottom first, into a single layer of the filesystem they
+// give - whiteouts are applied rather than kept. Hard links are resolved as
+// extracting the layers would leave them.
+pub fn squash_layers<P: AsRef<Path>>(tpaths: &[P], outpath: &Path, compression: Compression) -> LayerDigests {
+    println!("Reading {} layers", tpaths.len());
+    let squashed = overlay_layers(tpaths, false).resolve_links();
+
+    let entries: Vec<&(HashableHeader, EntrySource)> = squashed.values().collect();
+    println!("Writing {} entries to {}", entries.len(), outpath.display());
+    write_layer(outpath, compression, tpaths, &[], &entries)
+}
@@ -0,0 +1,75 @@
+use std::collections::BTreeMap;
+
+// A layer in the tree, containing files shared by all of its inputs. Inputs
+// are indexes of the tars being commonised, kept sorted.
+pub struct LayerNode {
+    pub inputs: Vec<usize>,
+    pub parent: Option<usize>,
+}
+
+// Build a forest of layers from the bytes that would be stored for each set of
+// inputs sharing some files. The first numinputs nodes are the individual
+// layers - pairs of subtrees are then merged, picking the merge which saves the
+// most bytes, until no merge saves anything.
+pub fn build_layer_tree(numinputs: usize, setcosts: &BTreeMap<Vec<usize>, u64>) -> Vec<LayerNode> {
+    let mut nodes: Vec<LayerNode> = (0..numinputs).map(|i| LayerNode { inputs: vec![i], parent: None })
+                                                  .collect();
+    loop {
+        let tops: Vec<usize> = (0..nodes.len()).filter(|&n| nodes[n].parent.is_none()).collect();
+        let mut best: Option<(u64, usize, usize)> = None;
+        for (i, &a) in tops.iter().enumerate() {
+            for &b in &tops[i + 1..] {
+                // Tops are the highest nodes within any set containing them,
+                // so anything covering both would be stored once rather than
+                // twice after merging
+                let merged = union(&nodes[a].inputs, &nodes[b].inputs);
+                let saving = setcosts.iter()
+                                     .filter(|&(set, _)| is_subset(&merged, set))
+                                     .fold(0, |sum, (_, cost)| sum + cost);
+                if saving > 0 && best.map_or(true, |(bestsaving, _, _)| saving > bestsaving) {
+                    best = Some((saving, a, b))
+                }
+            }
+        }
+        let (a, b) = match best {
+            Some((_, a, b)) => (a, b),
+            None => break,
+        };
+        let merged = union(&nodes[a].inputs, &nodes[b].inputs);
+        nodes.push(LayerNode { inputs: merged, parent: None });
+        let newnode = nodes.len() - 1;
+        nodes[a].parent = Some(newnode);
+        nodes[b].parent = Some(newnode);
+    }
+    nodes
+}
+
+// The nodes a file shared by the inputs should be stored in - the highest
+// nodes made up only of those inputs
+pub fn place(nodes: &[LayerNode], inputs: &[usize]) -> Vec<usize> {
+    (0..nodes.len()).filter(|&n| {
+        is_subset(&nodes[n].inputs, inputs) &&
+        nodes[n].parent.map_or(true, |p| !is_subset(&nodes[p].inputs, inputs))
+    }).collect()
+}
+
+// Layers to extract for an input, from the bottom up
+pub fn stack(nodes: &[LayerNode], input: usize) -> Vec<usize> {
+    let mut layers = vec![input];
+    while let Some(parent) = nodes[*layers.last().unwrap()].parent {
+        layers.push(parent)
+    }
+    layers.reverse();
+    layers
+}
+
+pub fn is_subset(small: &[usize], big: &[usize]) -> bool {
+    small.iter().all(|i| big.binary_search(i).is_ok())
+}
+
+fn union(a: &[usize], b: &[usize]) -> Vec<usize> {
+    let mut merged: Vec<usize> = a.iter().chain(b).cloned().collect();
+    merged.sort();
+    merged.dedup();
+    merged
+}
@@ -1,7 +1,15 @@
 use std::collections::HashMap;
+use std::fs;
 use std::hash::Hash;
 use std::io;
 use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crossbeam;
+
+use crypto::digest::Digest;
+use crypto::sha2::{Sha256, Sha512};
 
 pub fn find_common_keys<K, V>(hms: &[HashMap<K, V>]) -> Vec<K>
     where K: Clone + Eq + Hash
@@ -33,35 +41,113 @@ pub fn format_num_bytes(num: u64) -> String {
     }
 }
 
-pub fn readers_identical<R>(rs: &mut [R]) -> bool
-    where R: Read
+pub fn sha256_hex<R>(r: &mut R) -> String
+    where R: Read + ?Sized
 {
-    // This approach is slow:
-    // if f1.bytes().zip(f2.bytes()).all(|(b1, b2)| b1.unwrap() == b2.unwrap()) {
-    let mut brs: Vec<_> = rs.iter_mut()
-                            .map(|r| io::BufReader::with_capacity(512, r))
-                            .collect();
+    let mut hasher = Sha256::new();
+    let mut buf = [0u8; 64 * 1024];
     loop {
-        let numread = {
-            let bufs: Vec<_> = brs.iter_mut()
-                                  .map(|buf| buf.fill_buf().unwrap())
-                                  .collect();
-            let basebuf = bufs[0];
-            let numread = basebuf.len();
-            if numread == 0 {
-                return true;
-            }
-            if !bufs.iter().all(|buf| &basebuf == buf) {
-                return false;
-            }
-            numread
+        let numread = r.read(&mut buf).unwrap();
+        if numread == 0 {
+            return hasher.result_str();
+        }
+        hasher.input(&buf[..numread]);
+    }
+}
+
+// Passes writes through, keeping the digests a registry may refer to the data
+// by
+pub struct DigestWriter<W> {
+    inner: W,
+    sha256: Sha256,
+    sha512: Sha512,
+}
+
+impl<W: Write> DigestWriter<W> {
+    pub fn new(inner: W) -> DigestWriter<W> {
+        DigestWriter { inner: inner, sha256: Sha256::new(), sha512: Sha512::new() }
+    }
+
+    // Whether what's been written matches a digest like sha256:<hex>
+    pub fn matches(&mut self, digest: &str) -> bool {
+        let colonpos = digest.find(':').unwrap_or_else(|| panic!("Digest {} has no algorithm", digest));
+        let computed = match &digest[..colonpos] {
+            "sha256" => self.sha256.result_str(),
+            "sha512" => self.sha512.result_str(),
+            algorithm => panic!("Unsupported digest algorithm {}", algorithm),
         };
-        for br in &mut brs {
-            br.consume(numread);
+        computed == digest[colonpos + 1..]
+    }
+}
+
+impl<W: Write> Write for DigestWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let numwritten = try!(self.inner.write(buf));
+        self.sha256.input(&buf[..numwritten]);
+        self.sha512.input(&buf[..numwritten]);
+        Ok(numwritten)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// A scratch directory, removed when dropped - including when a panic unwinds
+// past it
+pub struct WorkDir(PathBuf);
+
+impl WorkDir {
+    pub fn create(path: PathBuf) -> WorkDir {
+        match fs::create_dir(&path) {
+            Ok(()) => WorkDir(path),
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                panic!("Work directory {} already exists, remove it if it's left from an earlier run",
+                       path.display())
+            },
+            Err(e) => panic!("Could not create {}: {}", path.display(), e),
         }
     }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for WorkDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
 }
 
 pub fn to_string_slices(strings: &[String]) -> Vec<&str> {
     strings.iter().map(|s| &s[..]).collect()
 }
+
+pub fn to_paths<S: AsRef<Path>>(names: &[S]) -> Vec<&Path> {
+    names.iter().map(|name| name.as_ref()).collect()
+}
+
+// Map over items with a pool of threads, keeping results in the same order
+pub fn parallel_map<T, R, F>(numthreads: usize, items: Vec<T>, f: F) -> Vec<R>
+    where T: Send, R: Send, F: Fn(T) -> R + Sync
+{
+    assert!(numthreads > 0, "need at least one thread");
+    let queue = Mutex::new(items.into_iter().enumerate());
+    let results = Mutex::new(vec![]);
+    crossbeam::scope(|scope| {
+        for _ in 0..numthreads {
+            scope.spawn(|| loop {
+                let next = queue.lock().unwrap().next();
+                let (i, item) = match next {
+                    Some(next) => next,
+                    None => break,
+                };
+                let result = f(item);
+                results.lock().unwrap().push((i, result));
+            });
+        }
+    });
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|&(i, _)| i);
+    results.into_iter().map(|(_, result)| result).collect()
+}